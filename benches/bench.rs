@@ -1,3 +1,4 @@
+#![cfg(nightly)]
 #![feature(test)]
 
 extern crate test;
@@ -18,8 +19,8 @@ extern "C" {
 #[bench]
 #[cfg(windows)]
 fn bench_lznt1_decompress_rtl(b: &mut Bencher) {
-    let compression_format_lznt1 = 0x0002 as u16;
-    let compression_engine_standard = 0x0000 as u16;
+    let compression_format_lznt1 = lzxpress::rtl::COMPRESSION_FORMAT_LZNT1;
+    let compression_engine_standard = lzxpress::rtl::COMPRESSION_ENGINE_STANDARD;
     use ntapi::ntrtl::{RtlDecompressBuffer};
 
     let compressed_data = include_bytes!("../tests/block1.compressed.bin");
@@ -78,6 +79,15 @@ fn bench_lznt1_decompress(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn bench_lznt1_validate(b: &mut Bencher) {
+    let compressed_data = include_bytes!("../tests/block1.compressed.bin");
+
+    b.iter(|| {
+        let _info = lzxpress::lznt1::validate(compressed_data).unwrap();
+    });
+}
+
 #[bench]
 fn bench_lznt1_decompress2_push(b: &mut Bencher) {
     let compressed_data = include_bytes!("../tests/block1.compressed.bin");
@@ -107,8 +117,8 @@ fn bench_lznt1_decompress2_no_push(b: &mut Bencher) {
 #[bench]
 #[cfg(windows)]
 fn bench_lzxpress_decompress_rtl(b: &mut Bencher) {
-    let compression_format_xpress = 0x0003 as u16;
-    let compression_engine_standard = 0x0000 as u16;
+    let compression_format_xpress = lzxpress::rtl::COMPRESSION_FORMAT_XPRESS;
+    let compression_engine_standard = lzxpress::rtl::COMPRESSION_ENGINE_STANDARD;
     use ntapi::ntrtl::{RtlDecompressBuffer};
 
     let compressed_data = include_bytes!("../tests/clusterfuzz-testcase-minimized-fuzz_ndr_drsuapi_TYPE_OUT-5724999789051904");
@@ -1,7 +1,21 @@
 extern crate cc;
 
+use std::env;
+use std::process::Command;
+
 fn main() {
     cc::Build::new()
         .file("tests/lznt1.c")
         .compile("libfoo.a");
-}
\ No newline at end of file
+
+    // Benchmarks rely on the unstable `test` crate, they are only built by a
+    // nightly compiler.
+    println!("cargo:rustc-check-cfg=cfg(nightly)");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Ok(output) = Command::new(rustc).arg("--version").output() {
+        if String::from_utf8_lossy(&output.stdout).contains("nightly") {
+            println!("cargo:rustc-cfg=nightly");
+        }
+    }
+}
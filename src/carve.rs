@@ -0,0 +1,274 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub use crate::error::Error;
+
+use crate::data::{self, TokenKind};
+use crate::lznt1;
+
+// The source is read in blocks of SCAN_BLOCK_SIZE bytes, each followed by
+// the window of the candidates starting at its end.
+const SCAN_BLOCK_SIZE:     usize = 0x10_0000;
+// Largest ScanOptions::window, the read buffer holds a block and a window
+const MAX_WINDOW:          usize = 0x100_0000;
+const LZNT1_SIGNATURE:     u16 = 0x3000;
+const LZNT1_CHUNK_SIZE:    usize = 0x1000;
+// A Plain LZ77 candidate has to get through a whole flag word, and to reach
+// a match early: encoders do not start a stream with a long run of literals.
+const LZ77_MIN_TOKENS:     usize = 32;
+const LZ77_MAX_LITERALS:   usize = 256;
+// Encoders turn runs of identical bytes into matches, no stream starts with
+// that many of them.
+const MIN_RUN_LENGTH:      usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Lznt1,
+    Lz77,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub kind:              StreamKind,
+    // Offsets in the source, end excluded
+    pub start:             u64,
+    pub end:               u64,
+    pub uncompressed_size: usize,
+    // 0.0 to 1.0
+    pub confidence:        f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub lznt1:       bool,
+    pub lz77:        bool,
+    // Candidates are only looked for at multiples of alignment
+    pub alignment:   usize,
+    // How far a candidate is followed, up to 16 MiB
+    pub window:      usize,
+    // Shorter streams are not reported
+    pub min_decoded: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            lznt1: true,
+            lz77: true,
+            alignment: 1,
+            window: 0x10000,
+            min_decoded: 256,
+        }
+    }
+}
+
+// Follows the chunk chain: every chunk carries the 0x3000 signature and
+// decodes, only the last one may hold less than 4096 bytes. A single chunk
+// is only reported when a zero header ends it.
+fn probe_lznt1(
+    in_buf: &[u8]
+) -> Option<(usize, usize, f32)>
+{
+    let mut chunks = lznt1::chunks(in_buf);
+    let mut count:             usize = 0;
+    let mut uncompressed_size: usize = 0;
+    let mut end:               usize = 0;
+    let mut terminated:        bool = false;
+
+    loop {
+        let chunk = match chunks.next() {
+            None => {
+                terminated = chunks.position() > end;
+                end = chunks.position();
+                break;
+            },
+            Some(Err(_)) => break,
+            Some(Ok(chunk)) => chunk,
+        };
+
+        if chunk.signature != LZNT1_SIGNATURE {
+            break;
+        }
+
+        let size = if chunk.compressed {
+            match lznt1::validate_chunk(chunk.data) {
+                Ok(size) => size,
+                Err(_) => break,
+            }
+        } else {
+            chunk.size
+        };
+
+        // Any 0x3xxx word looks like a stored chunk header, the stream has
+        // to start with a chunk that actually compresses.
+        if count == 0 && (!chunk.compressed || size <= chunk.size) {
+            return None;
+        }
+
+        count += 1;
+        uncompressed_size += size;
+        end = chunks.position();
+
+        if size < LZNT1_CHUNK_SIZE {
+            // A zero header may still terminate the stream.
+            if in_buf[end..].starts_with(&[0, 0]) {
+                end += 2;
+                terminated = true;
+            }
+            break;
+        }
+    }
+
+    if count == 0 || (count == 1 && !terminated) {
+        return None;
+    }
+
+    Some((end, uncompressed_size, 1.0 - 0.5f32.powi((count + terminated as usize) as i32)))
+}
+
+// Trial decode up to the first invalid token, the confidence is the share of
+// the output coming from matches.
+fn probe_lz77(
+    in_buf: &[u8]
+) -> Option<(usize, usize, f32)>
+{
+    let mut tokens = data::tokens(in_buf);
+    let mut count:             usize = 0;
+    let mut uncompressed_size: usize = 0;
+    let mut matched:           usize = 0;
+    let mut end:               usize = 0;
+
+    while let Some(Ok(token)) = tokens.next() {
+        match token.kind {
+            TokenKind::Literal(_) => uncompressed_size += 1,
+            TokenKind::Match { offset, length } => {
+                if offset > uncompressed_size {
+                    break;
+                }
+
+                uncompressed_size += length;
+                matched += length;
+            },
+        }
+
+        count += 1;
+        end = tokens.position();
+
+        if matched == 0 && count >= LZ77_MAX_LITERALS {
+            return None;
+        }
+    }
+
+    if count < LZ77_MIN_TOKENS || matched == 0 {
+        return None;
+    }
+
+    Some((end, uncompressed_size, matched as f32 / uncompressed_size as f32))
+}
+
+// Number of positions at the start of in_buf followed by at least
+// MIN_RUN_LENGTH identical bytes, none of them can start a stream.
+fn run_positions(
+    in_buf: &[u8]
+) -> usize
+{
+    let first = match in_buf.first() {
+        Some(first) => *first,
+        None => return 0,
+    };
+
+    let run_len = in_buf.iter().take_while(|b| **b == first).count();
+    (run_len + 1).saturating_sub(MIN_RUN_LENGTH)
+}
+
+fn read_full<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8]
+) -> io::Result<usize>
+{
+    let mut len: usize = 0;
+
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+// Scans from the current position of reader to its end. Once a candidate is
+// found, the scan resumes after it.
+pub fn scan<R: Read + Seek>(
+    reader: &mut R,
+    options: &ScanOptions
+) -> io::Result<Vec<Candidate>>
+{
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    if options.window > MAX_WINDOW {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", Error::InvalidParameter)));
+    }
+
+    let mut buf = vec![0u8; SCAN_BLOCK_SIZE + options.window];
+
+    let alignment = options.alignment.max(1) as u64;
+    let mut base = reader.stream_position()?;
+    let mut next = base.div_ceil(alignment) * alignment;
+
+    loop {
+        reader.seek(SeekFrom::Start(base))?;
+        let len = read_full(reader, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        let block_end = base + cmp::min(len, SCAN_BLOCK_SIZE) as u64;
+
+        while next < block_end {
+            let idx = (next - base) as usize;
+
+            let run = run_positions(&buf[idx..len]);
+            if run > 0 {
+                next = (next + run as u64).div_ceil(alignment) * alignment;
+                continue;
+            }
+
+            let window = &buf[idx..cmp::min(len, idx + options.window)];
+
+            let mut found = None;
+            if options.lznt1 {
+                found = probe_lznt1(window).map(|p| (StreamKind::Lznt1, p));
+            }
+            if found.is_none() && options.lz77 {
+                found = probe_lz77(window).map(|p| (StreamKind::Lz77, p));
+            }
+
+            match found {
+                Some((kind, (end, uncompressed_size, confidence))) if uncompressed_size >= options.min_decoded => {
+                    candidates.push(Candidate {
+                        kind,
+                        start: next,
+                        end: next + end as u64,
+                        uncompressed_size,
+                        confidence,
+                    });
+
+                    next = (next + end as u64).div_ceil(alignment) * alignment;
+                },
+                _ => next += alignment,
+            }
+        }
+
+        if len <= SCAN_BLOCK_SIZE {
+            break;
+        }
+
+        base = block_end;
+    }
+
+    Ok(candidates)
+}
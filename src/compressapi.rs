@@ -0,0 +1,233 @@
+use std::cmp;
+use std::convert::TryFrom;
+
+pub use crate::error::Error;
+
+use crate::data;
+use crate::huffman;
+
+// Buffer mode of the Windows Compression API (Compress() without
+// COMPRESS_RAW): a 24-byte header followed by blocks, each preceded by its
+// compressed size. Every block but the last one holds block_size bytes once
+// decompressed, a block as large as its uncompressed size is stored raw.
+//
+//   0x00 u32 magic (0A 51 E5 C0)
+//   0x04 u8  header size
+//   0x05 u8  COMPRESS_ALGORITHM_*
+//   0x06 u16 reserved
+//   0x08 u64 uncompressed size
+//   0x10 u64 block size
+const COMPRESSAPI_MAGIC:       usize = 0xC0E5_510A;
+pub const HEADER_SIZE:         usize = 0x18;
+pub const BLOCK_HEADER_SIZE:   usize = 4;
+pub const DEFAULT_BLOCK_SIZE:  usize = 0x10_0000;
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+macro_rules! load64le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u64::from($src[$idx + 7]) << 56)
+            | (u64::from($src[$idx + 6]) << 48)
+            | (u64::from($src[$idx + 5]) << 40)
+            | (u64::from($src[$idx + 4]) << 32)
+            | (u64::from($src[$idx + 3]) << 24)
+            | (u64::from($src[$idx + 2]) << 16)
+            | (u64::from($src[$idx + 1]) << 8)
+            | u64::from($src[$idx])) as usize;
+        }
+    }
+}
+
+// COMPRESS_ALGORITHM_*
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Mszip,
+    Xpress,
+    XpressHuff,
+    Lzms,
+}
+
+impl Algorithm {
+    pub fn from_u8(
+        value: u8
+    ) -> Result<Algorithm, Error>
+    {
+        match value {
+            2 => Ok(Algorithm::Mszip),
+            3 => Ok(Algorithm::Xpress),
+            4 => Ok(Algorithm::XpressHuff),
+            5 => Ok(Algorithm::Lzms),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            Algorithm::Mszip => 2,
+            Algorithm::Xpress => 3,
+            Algorithm::XpressHuff => 4,
+            Algorithm::Lzms => 5,
+        }
+    }
+
+    // MSZIP and LZMS are not implemented by this crate.
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Algorithm::Xpress | Algorithm::XpressHuff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub algorithm:         Algorithm,
+    pub uncompressed_size: u64,
+    pub block_size:        u64,
+}
+
+impl Header {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<Header, Error>
+    {
+        let magic:             usize;
+        let uncompressed_size: usize;
+        let block_size:        usize;
+
+        if in_buf.len() < HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load32le!(magic, in_buf, 0);
+        if magic != COMPRESSAPI_MAGIC || usize::from(in_buf[4]) != HEADER_SIZE {
+            return Err(Error::InvalidSignature);
+        }
+
+        let algorithm = Algorithm::from_u8(in_buf[5])?;
+        load64le!(uncompressed_size, in_buf, 8);
+        load64le!(block_size, in_buf, 16);
+
+        if block_size == 0 && uncompressed_size != 0 {
+            return Err(Error::CorruptedData);
+        }
+
+        Ok(Header {
+            algorithm,
+            uncompressed_size: uncompressed_size as u64,
+            block_size: block_size as u64,
+        })
+    }
+
+    pub fn write(
+        &self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        out_buf.extend_from_slice(&(COMPRESSAPI_MAGIC as u32).to_le_bytes());
+        out_buf.push(HEADER_SIZE as u8);
+        out_buf.push(self.algorithm.to_u8());
+        out_buf.extend_from_slice(&[0, 0]);
+        out_buf.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        out_buf.extend_from_slice(&self.block_size.to_le_bytes());
+    }
+}
+
+fn decompress_block(
+    in_buf: &[u8],
+    algorithm: Algorithm,
+    out_len: usize
+) -> Result<Vec<u8>, Error>
+{
+    if in_buf.len() == out_len {
+        return Ok(in_buf.to_vec());
+    }
+
+    match algorithm {
+        Algorithm::Xpress => data::decompress_exact(in_buf, out_len),
+        Algorithm::XpressHuff => huffman::decompress(in_buf, out_len),
+        Algorithm::Mszip | Algorithm::Lzms => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let header = Header::parse(in_buf)?;
+    let mut in_idx: usize = HEADER_SIZE;
+
+    // The sizes are not trusted: every block takes at least its size field,
+    // and the preallocation is bounded by the input.
+    let max_blocks = ((in_buf.len() - HEADER_SIZE) / BLOCK_HEADER_SIZE) as u64;
+    if max_blocks.saturating_mul(header.block_size) < header.uncompressed_size {
+        return Err(Error::MemLimit);
+    }
+
+    let uncompressed_size = usize::try_from(header.uncompressed_size).map_err(|_| Error::MemLimit)?;
+    let block_size = usize::try_from(header.block_size).unwrap_or(usize::MAX);
+    let mut out_buf: Vec<u8> = Vec::with_capacity(cmp::min(uncompressed_size, in_buf.len().saturating_mul(4)));
+
+    while out_buf.len() < uncompressed_size {
+        let compressed_size: usize;
+
+        if (in_buf.len() - in_idx) < BLOCK_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load32le!(compressed_size, in_buf, in_idx);
+        in_idx += BLOCK_HEADER_SIZE;
+
+        if compressed_size > (in_buf.len() - in_idx) {
+            return Err(Error::MemLimit);
+        }
+
+        let out_len = block_size.min(uncompressed_size - out_buf.len());
+        let block = decompress_block(&in_buf[in_idx..in_idx + compressed_size], header.algorithm, out_len)?;
+
+        if block.len() != out_len {
+            return Err(Error::CorruptedData);
+        }
+
+        out_buf.extend_from_slice(&block);
+        in_idx += compressed_size;
+    }
+
+    Ok(out_buf)
+}
+
+pub fn compress(
+    in_buf: &[u8],
+    algorithm: Algorithm
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(HEADER_SIZE + in_buf.len());
+
+    Header {
+        algorithm,
+        uncompressed_size: in_buf.len() as u64,
+        block_size: DEFAULT_BLOCK_SIZE as u64,
+    }.write(&mut out_buf);
+
+    for block in in_buf.chunks(DEFAULT_BLOCK_SIZE) {
+        let compressed = match algorithm {
+            Algorithm::Xpress => data::compress(block)?,
+            Algorithm::XpressHuff => huffman::compress(block)?,
+            Algorithm::Mszip | Algorithm::Lzms => return Err(Error::UnsupportedAlgorithm),
+        };
+
+        let body = if compressed.len() < block.len() { &compressed[..] } else { block };
+
+        out_buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out_buf.extend_from_slice(body);
+    }
+
+    Ok(out_buf)
+}
@@ -38,124 +38,839 @@ pub fn decompress(
     in_buf: &[u8]
 ) -> Result<Vec<u8>, Error>
 {
-    let mut out_idx:    usize = 0;
-    let mut in_idx:     usize = 0;
-    let mut nibble_idx: usize = 0;
+    Ok(decompress_limit(in_buf, usize::MAX, false)?.0)
+}
+
+// Stops as soon as out_max bytes have been produced, whatever follows in
+// in_buf (e.g. padding) is ignored. A match running past out_max fails.
+pub fn decompress_bounded(
+    in_buf: &[u8],
+    out_max: usize
+) -> Result<Vec<u8>, Error>
+{
+    Ok(decompress_limit(in_buf, out_max, true)?.0)
+}
+
+// Same as decompress_bounded, along with the number of input bytes the
+// stream took. A flag word following the last token is not counted.
+pub fn decompress_consumed(
+    in_buf: &[u8],
+    out_max: usize
+) -> Result<(Vec<u8>, usize), Error>
+{
+    decompress_limit(in_buf, out_max, true)
+}
+
+// The whole of in_buf has to decompress to exactly out_len bytes.
+pub fn decompress_exact(
+    in_buf: &[u8],
+    out_len: usize
+) -> Result<Vec<u8>, Error>
+{
+    let (out_buf, _) = decompress_limit(in_buf, out_len, false)?;
 
-    let mut flags:      usize = 0;
-    let mut flag_count: usize = 0;
+    if out_buf.len() != out_len {
+        return Err(Error::CorruptedData);
+    }
 
-    let mut length: usize;
-    let mut offset: usize;
+    Ok(out_buf)
+}
 
+fn decompress_limit(
+    in_buf: &[u8],
+    out_max: usize,
+    stop_at_max: bool
+) -> Result<(Vec<u8>, usize), Error>
+{
     let mut out_buf: Vec<u8> = Vec::new();
+    let mut tokens = tokens(in_buf);
+
+    while !(stop_at_max && out_buf.len() >= out_max) {
+        let token = match tokens.next() {
+            Some(token) => token?,
+            None => break,
+        };
+
+        match token.kind {
+            TokenKind::Literal(b) => {
+                if out_buf.len() >= out_max {
+                    return Err(Error::MemLimit);
+                }
 
-    while in_idx < in_buf.len() {
-        if flag_count == 0 {
-            if (in_idx + 3) >= in_buf.len() {
-                return Err(Error::MemLimit);
+                out_buf.push(b);
+            },
+            TokenKind::Match { offset, length } => {
+                if length > (out_max - out_buf.len()) {
+                    return Err(Error::MemLimit);
+                }
+
+                if offset > out_buf.len() {
+                    return Err(Error::CorruptedData);
+                }
+
+                for _i in 0..length {
+                    out_buf.push(out_buf[out_buf.len() - offset]);
+                }
+            },
+        }
+    }
+
+    Ok((out_buf, tokens.position()))
+}
+
+// Where an output byte (or the first byte of a run) comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // Copied from the input
+    Literal {
+        in_idx: usize,
+    },
+    // Copied by the match at in_idx from earlier output
+    Match {
+        in_idx:      usize,
+        src_out_idx: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProvenanceRun {
+    pub out_idx: usize,
+    pub len:     usize,
+    pub source:  Source,
+}
+
+// Output to input map, one run per match and per sequence of literals that
+// are contiguous in the input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Provenance {
+    runs: Vec<ProvenanceRun>,
+}
+
+impl Provenance {
+    pub fn runs(&self) -> &[ProvenanceRun] {
+        &self.runs
+    }
+
+    pub fn lookup(
+        &self,
+        out_idx: usize
+    ) -> Option<Source>
+    {
+        let idx = self.runs.partition_point(|run| run.out_idx + run.len <= out_idx);
+        let run = self.runs.get(idx)?;
+
+        if out_idx < run.out_idx {
+            return None;
+        }
+
+        let delta = out_idx - run.out_idx;
+        Some(match run.source {
+            Source::Literal { in_idx } => Source::Literal { in_idx: in_idx + delta },
+            Source::Match { in_idx, src_out_idx } => Source::Match { in_idx, src_out_idx: src_out_idx + delta },
+        })
+    }
+
+    pub(crate) fn push_literals(
+        &mut self,
+        out_idx: usize,
+        in_idx: usize,
+        len: usize
+    )
+    {
+        if let Some(last) = self.runs.last_mut() {
+            if let Source::Literal { in_idx: last_in_idx } = last.source {
+                if last.out_idx + last.len == out_idx && last_in_idx + last.len == in_idx {
+                    last.len += len;
+                    return;
+                }
+            }
+        }
+
+        self.runs.push(ProvenanceRun { out_idx, len, source: Source::Literal { in_idx } });
+    }
+
+    pub(crate) fn push_match(
+        &mut self,
+        out_idx: usize,
+        in_idx: usize,
+        offset: usize,
+        length: usize
+    )
+    {
+        self.runs.push(ProvenanceRun {
+            out_idx,
+            len: length,
+            source: Source::Match { in_idx, src_out_idx: out_idx - offset },
+        });
+    }
+}
+
+// Same as decompress, along with the origin of every output byte.
+pub fn decompress_with_provenance(
+    in_buf: &[u8]
+) -> Result<(Vec<u8>, Provenance), Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+    let mut provenance = Provenance::default();
+
+    for token in tokens(in_buf) {
+        let token = token?;
+
+        match token.kind {
+            TokenKind::Literal(b) => {
+                provenance.push_literals(out_buf.len(), token.in_idx, 1);
+                out_buf.push(b);
+            },
+            TokenKind::Match { offset, length } => {
+                if offset > out_buf.len() {
+                    return Err(Error::CorruptedData);
+                }
+
+                provenance.push_match(out_buf.len(), token.in_idx, offset, length);
+                for _i in 0..length {
+                    out_buf.push(out_buf[out_buf.len() - offset]);
+                }
+            },
+        }
+    }
+
+    Ok((out_buf, provenance))
+}
+
+// Decodes a fragment starting on a flag word in the middle of a stream, with
+// up to 8 KiB of history missing. Bytes copied from that history are None,
+// and so are the bytes later copied from them. No length nibble may be
+// pending at the cut: a match sharing a nibble byte read before the
+// fragment would be decoded from the wrong bytes. decompress_fragment_candidates
+// takes fragments cut anywhere.
+pub fn decompress_fragment(
+    in_buf: &[u8]
+) -> Result<Vec<Option<u8>>, Error>
+{
+    let mut out_buf: Vec<Option<u8>> = Vec::new();
+
+    for token in tokens(in_buf) {
+        push_fragment_token(&mut out_buf, token?.kind);
+    }
+
+    Ok(out_buf)
+}
+
+fn push_fragment_token(
+    out_buf: &mut Vec<Option<u8>>,
+    kind: TokenKind
+)
+{
+    match kind {
+        TokenKind::Literal(b) => out_buf.push(Some(b)),
+        TokenKind::Match { offset, length } => {
+            for _i in 0..length {
+                let b = match out_buf.len().checked_sub(offset) {
+                    Some(src_idx) => out_buf[src_idx],
+                    None => None,
+                };
+                out_buf.push(b);
+            }
+        },
+    }
+}
+
+// Starts tried by decompress_fragment_candidates: a flag word and the 32
+// tokens it describes, each up to 10 bytes long.
+const FRAGMENT_MAX_SKIP: usize = 4 + 32 * 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    // Input offset of the flag word the decode starts on
+    pub in_idx:  usize,
+    // Number of tried starts whose parses end up on this one
+    pub starts:  usize,
+    // Up to the end of the input or the token it cuts
+    pub out_buf: Vec<Option<u8>>,
+}
+
+fn find_root(
+    parent: &mut [usize],
+    mut start: usize
+) -> usize
+{
+    while parent[start] != start {
+        parent[start] = parent[parent[start]];
+        start = parent[start];
+    }
+
+    start
+}
+
+// Decodes a fragment cut anywhere in a stream, even inside a token or with
+// a length nibble pending. Every one of the first bytes is tried as a flag
+// word with nothing pending. Two parses reading the same flag word from
+// there on decode the same tokens: the parses still apart past the tried
+// starts give one candidate each, decoded from the flag word all the
+// parses joining it read (its only start when none join). The format does
+// not tell which one is the stream, the candidates come in input order and
+// the caller picks the one whose output makes sense. Parses running into
+// corrupted data are dropped.
+pub fn decompress_fragment_candidates(
+    in_buf: &[u8]
+) -> Vec<Fragment>
+{
+    let max_skip = cmp::min(FRAGMENT_MAX_SKIP, in_buf.len());
+
+    // Start that first read a flag word at each input offset, with no
+    // length nibble pending
+    let mut owner: Vec<usize> = vec![usize::MAX; in_buf.len()];
+    let mut parent: Vec<usize> = (0..max_skip).collect();
+    let mut meet: Vec<usize> = vec![0; max_skip];
+    let mut failed: Vec<bool> = vec![false; max_skip];
+
+    // From the last start: the flag words of a parse are all past its start,
+    // so an earlier start finds them owned.
+    for start in (0..max_skip).rev() {
+        let mut tokens = tokens(&in_buf[start..]);
+
+        loop {
+            if tokens.flag_count == 0 && tokens.nibble_idx == 0 && (start + tokens.in_idx) < in_buf.len() {
+                let flag_idx = start + tokens.in_idx;
+
+                if owner[flag_idx] != usize::MAX {
+                    let root = find_root(&mut parent, owner[flag_idx]);
+                    parent[start] = root;
+                    meet[root] = cmp::max(meet[root], flag_idx);
+                    break;
+                }
+
+                owner[flag_idx] = start;
+
+                // Parses meeting further on are kept apart
+                if flag_idx >= max_skip {
+                    break;
+                }
+            }
+
+            match tokens.next() {
+                None => break,
+                Some(Ok(_)) => (),
+                // The input ends inside a token
+                Some(Err(Error::MemLimit)) => break,
+                Some(Err(_)) => {
+                    failed[start] = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    let mut starts: Vec<usize> = vec![0; max_skip];
+    for start in 0..max_skip {
+        let root = find_root(&mut parent, start);
+        starts[root] += 1;
+    }
+
+    let mut candidates: Vec<Fragment> = Vec::new();
+
+    for root in 0..max_skip {
+        if starts[root] == 0 || failed[root] {
+            continue;
+        }
+
+        let in_idx = cmp::max(root, meet[root]);
+        let mut out_buf: Vec<Option<u8>> = Vec::new();
+
+        for token in tokens(&in_buf[in_idx..]) {
+            match token {
+                Ok(token) => push_fragment_token(&mut out_buf, token.kind),
+                Err(_) => break,
+            }
+        }
+
+        candidates.push(Fragment { in_idx, starts: starts[root], out_buf });
+    }
+
+    candidates.sort_by_key(|candidate| candidate.in_idx);
+
+    candidates
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeFailure {
+    pub error:   Error,
+    // Input offset of the token that failed (of its chunk for LZNT1)
+    pub in_idx:  usize,
+    // Output produced before the failure
+    pub out_idx: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialOutput {
+    pub out_buf: Vec<u8>,
+    // None when the whole stream decoded
+    pub failure: Option<DecodeFailure>,
+}
+
+impl PartialOutput {
+    pub fn is_complete(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+// Keeps the output produced up to the first error instead of dropping it.
+pub fn decompress_lenient(
+    in_buf: &[u8]
+) -> PartialOutput
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+    let mut tokens = tokens(in_buf);
+
+    loop {
+        let in_idx = tokens.position();
+        let error = match tokens.next() {
+            None => break,
+            Some(Err(e)) => e,
+            Some(Ok(token)) => match token.kind {
+                TokenKind::Literal(b) => {
+                    out_buf.push(b);
+                    continue;
+                },
+                TokenKind::Match { offset, length } => {
+                    if offset <= out_buf.len() {
+                        for _i in 0..length {
+                            out_buf.push(out_buf[out_buf.len() - offset]);
+                        }
+                        continue;
+                    }
+                    Error::CorruptedData
+                },
+            },
+        };
+
+        let out_idx = out_buf.len();
+        return PartialOutput { out_buf, failure: Some(DecodeFailure { error, in_idx, out_idx }) };
+    }
+
+    PartialOutput { out_buf, failure: None }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamInfo {
+    // Input bytes making up the stream
+    pub compressed_size:   usize,
+    pub uncompressed_size: usize,
+}
+
+// Walks the whole stream and checks every match against the output length,
+// without producing any output.
+pub fn validate(
+    in_buf: &[u8]
+) -> Result<StreamInfo, Error>
+{
+    let mut info = StreamInfo::default();
+
+    for token in tokens(in_buf) {
+        match token?.kind {
+            TokenKind::Literal(_) => info.uncompressed_size += 1,
+            TokenKind::Match { offset, length } => {
+                if offset > info.uncompressed_size {
+                    return Err(Error::CorruptedData);
+                }
+
+                info.uncompressed_size += length;
+            },
+        }
+    }
+
+    info.compressed_size = in_buf.len();
+
+    Ok(info)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Literal(u8),
+    Match {
+        offset: usize,
+        length: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind:       TokenKind,
+    // Input offset of the literal byte or of the match
+    pub in_idx:     usize,
+    // Output offset the token decodes to
+    pub out_idx:    usize,
+    // Input offset of the flag word and bit (31 first) of the token
+    pub flag_idx:   usize,
+    pub flag_bit:   usize,
+    // Input offset of the byte holding the length nibble, if the match used one
+    pub nibble_idx: Option<usize>,
+}
+
+// Walks the tokens the way decompress() reads them. Match offsets are not
+// checked against the output produced so far, decompress() does that.
+pub struct Tokens<'a> {
+    in_buf:     &'a [u8],
+    in_idx:     usize,
+    out_idx:    usize,
+    nibble_idx: usize,
+    flags:      usize,
+    flag_count: usize,
+    flag_idx:   usize,
+    done:       bool,
+}
+
+impl Tokens<'_> {
+    // Input bytes walked so far
+    pub fn position(&self) -> usize {
+        self.in_idx
+    }
+
+    fn fail(
+        &mut self,
+        error: Error
+    ) -> Option<Result<Token, Error>>
+    {
+        self.done = true;
+        Some(Err(error))
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<Token, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let in_buf = self.in_buf;
+        let mut length: usize;
+
+        if self.done {
+            return None;
+        }
+
+        if self.flag_count == 0 && self.in_idx < in_buf.len() {
+            if (self.in_idx + 3) >= in_buf.len() {
+                return self.fail(Error::MemLimit);
             }
 
-            load32le!(flags, in_buf, in_idx);
-            in_idx += mem::size_of::<u32>();
-            flag_count = 32;
+            load32le!(self.flags, in_buf, self.in_idx);
+            self.flag_idx = self.in_idx;
+            self.in_idx += mem::size_of::<u32>();
+            self.flag_count = 32;
         }
 
-        flag_count -= 1;
+        // The end of the input ends the stream, right after a flag word
+        // as well: the remaining flag bits are padding whatever their value
+        // ([MS-XCA] 2.4.4 sets them, Samba clears them).
+        if self.in_idx >= in_buf.len() {
+            self.done = true;
+            return None;
+        }
+
+        self.flag_count -= 1;
+
+        let token_idx = self.in_idx;
+        let flag_bit = self.flag_count;
 
         // Check whether the bit specified by flag_count is set or not
         // set in flags. For example, if flag_count has value 4
         // check whether the 4th bit of the value in flags is set.
-        if (flags & (1 << flag_count)) == 0 {
-            if in_idx >= in_buf.len() {
-                return Err(Error::MemLimit);
+        if (self.flags & (1 << self.flag_count)) == 0 {
+            if self.in_idx >= in_buf.len() {
+                return self.fail(Error::MemLimit);
             }
-            out_buf.push(in_buf[in_idx]);
 
-            in_idx += mem::size_of::<u8>();
-            out_idx += mem::size_of::<u8>();
-        } else {
-            if (in_idx + 1) >= in_buf.len() {
-                return Err(Error::MemLimit);
-            }
+            let token = Token {
+                kind: TokenKind::Literal(in_buf[self.in_idx]),
+                in_idx: token_idx,
+                out_idx: self.out_idx,
+                flag_idx: self.flag_idx,
+                flag_bit,
+                nibble_idx: None,
+            };
 
-            load16le!(length, in_buf, in_idx);
-            in_idx += mem::size_of::<u16>();
+            self.in_idx += mem::size_of::<u8>();
+            self.out_idx += mem::size_of::<u8>();
 
-            offset = (length / 8) + 1;
-            length = length % 8;
+            return Some(Ok(token));
+        }
 
-            if length == 7 {
-                if nibble_idx == 0 {
-                    if in_idx >= in_buf.len() {
-                        return Err(Error::MemLimit);
-                    }
+        if (self.in_idx + 1) >= in_buf.len() {
+            return self.fail(Error::MemLimit);
+        }
 
-                    length = (in_buf[in_idx] % 16).into();
-                    nibble_idx = in_idx;
-                    in_idx += mem::size_of::<u8>();
-                } else {
-                    if nibble_idx >= in_buf.len() {
-                        return Err(Error::MemLimit);
-                    }
+        load16le!(length, in_buf, self.in_idx);
+        self.in_idx += mem::size_of::<u16>();
+
+        let offset = (length / 8) + 1;
+        let mut nibble_idx = None;
+        length %= 8;
 
-                    length = (in_buf[nibble_idx] / 16).into();
-                    nibble_idx = 0;
+        if length == 7 {
+            if self.nibble_idx == 0 {
+                if self.in_idx >= in_buf.len() {
+                    return self.fail(Error::MemLimit);
                 }
 
-                if length == 15 {
-                    if in_idx >= in_buf.len() {
-                        return Err(Error::MemLimit);
-                    }
+                length = (in_buf[self.in_idx] % 16).into();
+                self.nibble_idx = self.in_idx;
+                nibble_idx = Some(self.in_idx);
+                self.in_idx += mem::size_of::<u8>();
+            } else {
+                if self.nibble_idx >= in_buf.len() {
+                    return self.fail(Error::MemLimit);
+                }
 
-                    length = in_buf[in_idx].into();
-                    in_idx += mem::size_of::<u8>();
+                length = (in_buf[self.nibble_idx] / 16).into();
+                nibble_idx = Some(self.nibble_idx);
+                self.nibble_idx = 0;
+            }
 
-                    if length == 255 {
-                        if (in_idx + 1) >= in_buf.len() {
-                            return Err(Error::MemLimit);
-                        }
+            if length == 15 {
+                if self.in_idx >= in_buf.len() {
+                    return self.fail(Error::MemLimit);
+                }
 
-                        load16le!(length, in_buf, in_idx);
-                        in_idx += mem::size_of::<u16>();
+                length = in_buf[self.in_idx].into();
+                self.in_idx += mem::size_of::<u8>();
 
-                        if length == 0 {
-                            load32le!(length, in_buf, in_idx);
-                            in_idx += mem::size_of::<u32>();
-                        }
+                if length == 255 {
+                    if (self.in_idx + 1) >= in_buf.len() {
+                        return self.fail(Error::MemLimit);
+                    }
+
+                    load16le!(length, in_buf, self.in_idx);
+                    self.in_idx += mem::size_of::<u16>();
 
-                        if length < 15 + 7 {
-                            return Err(Error::CorruptedData);
+                    if length == 0 {
+                        if (self.in_idx + 3) >= in_buf.len() {
+                            return self.fail(Error::MemLimit);
                         }
-                        length -= 15 + 7;
+
+                        load32le!(length, in_buf, self.in_idx);
+                        self.in_idx += mem::size_of::<u32>();
+                    }
+
+                    if length < 15 + 7 {
+                        return self.fail(Error::CorruptedData);
                     }
-                    length += 15;
+                    length -= 15 + 7;
                 }
-                length += 7;
+                length += 15;
             }
-            length += 3;
+            length += 7;
+        }
+        length += 3;
 
-            for _i in 0..length {
-                if offset > out_idx {
-                    return Err(Error::CorruptedData);
+        let token = Token {
+            kind: TokenKind::Match { offset, length },
+            in_idx: token_idx,
+            out_idx: self.out_idx,
+            flag_idx: self.flag_idx,
+            flag_bit,
+            nibble_idx,
+        };
+
+        self.out_idx += length;
+
+        Some(Ok(token))
+    }
+}
+
+pub fn tokens(
+    in_buf: &[u8]
+) -> Tokens<'_>
+{
+    Tokens {
+        in_buf,
+        in_idx: 0,
+        out_idx: 0,
+        nibble_idx: 0,
+        flags: 0,
+        flag_count: 0,
+        flag_idx: 0,
+        done: false,
+    }
+}
+
+// Packs literals and matches, their flags and the shared length nibbles.
+struct Encoder {
+    out_buf:      Vec<u8>,
+    flags:        u32,
+    flag_count:   u32,
+    flag_out_off: usize,
+    nibble_index: usize,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder {
+            // Flag placeholder
+            out_buf: vec![0, 0, 0, 0],
+            flags: 0,
+            flag_count: 0,
+            flag_out_off: 0,
+            nibble_index: 0,
+        }
+    }
+
+    fn push_flag(
+        &mut self,
+        flag: u32
+    )
+    {
+        self.flags = (self.flags << 1) | flag;
+        self.flag_count += 1;
+        if self.flag_count == 32 {
+            store32le!(self.out_buf, self.flag_out_off, self.flags);
+            self.flag_count = 0;
+            self.flag_out_off = self.out_buf.len();
+            self.out_buf.extend_from_slice(&[0, 0, 0, 0]);
+        }
+    }
+
+    fn literal(
+        &mut self,
+        b: u8
+    )
+    {
+        self.out_buf.push(b);
+        self.push_flag(0);
+    }
+
+    // offset in 1..=8192, length of at least 3
+    fn copy(
+        &mut self,
+        offset: usize,
+        length: usize
+    )
+    {
+        let out_buf = &mut self.out_buf;
+        let mut match_len = length - 3;
+        let match_off = offset - 1;
+        let metadata: usize;
+
+        if match_len < 7 {
+            // Classical meta-data
+            metadata = (match_off << 3) + match_len;
+            out_buf.push(metadata as u8);
+            out_buf.push((metadata >> 8) as u8);
+        } else {
+            let mut has_extra_len: bool = false;
+
+            metadata = (match_off << 3) | 7;
+            out_buf.push(metadata as u8);
+            out_buf.push((metadata >> 8) as u8);
+
+            match_len -= 7;
+
+            if self.nibble_index == 0 {
+                self.nibble_index = out_buf.len();
+                if match_len < 15 {
+                    out_buf.push(match_len as u8);
+                } else {
+                    out_buf.push(15);
+                    has_extra_len = true;
+                }
+            } else {
+                if match_len < 15 {
+                    out_buf[self.nibble_index] |= (match_len << 4) as u8;
+                } else {
+                    out_buf[self.nibble_index] |= (15 << 4) as u8;
+                    has_extra_len = true;
                 }
+                self.nibble_index = 0;
+            }
+
+            if has_extra_len {
+                match_len -= 15;
+
+                if match_len < 255 {
+                    out_buf.push(match_len as u8);
+                } else {
+                    out_buf.push(255);
 
-                out_buf.push(out_buf[out_idx - offset]);
-                out_idx += mem::size_of::<u8>();
+                    match_len += 7 + 15;
+
+                    if match_len < (1 << 16) {
+                        out_buf.push(match_len as u8);
+                        out_buf.push((match_len >> 8) as u8);
+                    } else {
+                        out_buf.push(0);
+                        out_buf.push(0);
+                        out_buf.push(match_len as u8);
+                        out_buf.push((match_len >> 8) as u8);
+                        out_buf.push((match_len >> 16) as u8);
+                        out_buf.push((match_len >> 24) as u8);
+                    }
+                }
             }
         }
+
+        self.push_flag(1);
     }
 
-    Ok(out_buf)
+    // The last flag word can be entirely unused.
+    fn finish(
+        mut self,
+        pad_ones: bool
+    ) -> Vec<u8>
+    {
+        let padding = 32 - self.flag_count;
+        let mut flags = u64::from(self.flags) << padding;
+        if pad_ones {
+            flags |= (1u64 << padding) - 1;
+        }
+        store32le!(self.out_buf, self.flag_out_off, flags as u32);
+
+        self.out_buf
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressOptions {
+    // Farthest match looked for, 1 to 8192
+    pub max_offset: usize,
+    // Longest match looked for, at least 3
+    pub max_length: usize,
+    // Set the unused bits of the last flag word, they are left clear otherwise
+    pub pad_ones:   bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> CompressOptions {
+        CompressOptions {
+            max_offset: 8192,
+            max_length: 8192,
+            pad_ones: true,
+        }
+    }
+}
+
+impl CompressOptions {
+    // Same output as lzxpress_compress() of Samba's lib/compression/lzxpress.c
+    pub fn samba() -> CompressOptions {
+        CompressOptions {
+            max_offset: 0x1FFF,
+            max_length: 255 + 15 + 7 + 3,
+            pad_ones: false,
+        }
+    }
 }
 
 pub fn compress(
     in_buf: &[u8]
 ) -> Result<Vec<u8>, Error>
+{
+    compress_with(in_buf, &CompressOptions::default())
+}
+
+pub fn compress_with(
+    in_buf: &[u8],
+    options: &CompressOptions
+) -> Result<Vec<u8>, Error>
 {
 
     let mut in_idx:    usize = 0;
-    let mut out_idx:   usize;
     let mut byte_left: usize;
 
     let mut max_off:   usize;
@@ -164,26 +879,14 @@ pub fn compress(
     let mut max_len:  usize;
     let mut best_len: usize;
 
-    let mut flags:        u32 = 0;
-    let mut flag_count:   u32 = 0;
-    let mut flag_out_off: usize = 0;
-    let mut nibble_index: usize = 0;
-
-    let mut metadata_size: usize;
-    let mut metadata:      usize;
-    let mut _dest_off:     usize;
-
     let mut str1_off: usize;
     let mut str2_off: usize;
 
-    let mut out_buf: Vec<u8> = Vec::new();
+    let mut encoder = Encoder::new();
 
-    // Flag placeholder
-    out_buf.push(0);
-    out_buf.push(0);
-    out_buf.push(0);
-    out_buf.push(0);
-    out_idx = mem::size_of::<u32>();
+    if options.max_offset == 0 || options.max_offset > 8192 || options.max_length < 3 {
+        return Err(Error::InvalidParameter);
+    }
 
     while in_idx < in_buf.len() {
         let mut found: bool = false;
@@ -195,7 +898,7 @@ pub fn compress(
         best_len = 2;
         match_off = 0;
 
-        max_off = cmp::min(8192, max_off);
+        max_off = cmp::min(options.max_offset, max_off);
 
         // search for the longest match in the window for the lookahead buffer
         for offset in 1..=max_off {
@@ -203,7 +906,7 @@ pub fn compress(
             str2_off = str1_off - offset;
 
             // maximum len we can encode into metadata
-            max_len = cmp::min(8192, byte_left);
+            max_len = cmp::min(options.max_length, byte_left);
 
             for i in 0..max_len {
                 if in_buf[str1_off + i] != in_buf[str2_off + i] {
@@ -222,119 +925,37 @@ pub fn compress(
         }
 
         if !found {
-            out_buf.push(in_buf[in_idx]);
-            out_idx += 1;
+            encoder.literal(in_buf[in_idx]);
             in_idx += 1;
-
-            flags <<= 1;
-            flag_count += 1;
-            if flag_count == 32 {
-                store32le!(out_buf, flag_out_off, flags);
-                flag_count = 0;
-                flag_out_off = out_idx;
-                out_buf.push(0);
-                out_buf.push(0);
-                out_buf.push(0);
-                out_buf.push(0);
-                out_idx += mem::size_of::<u32>();
-            }
         } else {
-            let mut match_len = best_len;
-            metadata_size = 0;
-
-            match_len -= 3;
-            match_off -= 1;
-
-            if match_len < 7 {
-                // Classical meta-data
-                metadata = (match_off  << 3) + match_len;
-                out_buf.push(metadata as u8);
-                out_buf.push((metadata >> 8) as u8);
-                metadata_size += mem::size_of::<u16>();
-            } else {
-                let mut has_extra_len: bool = false;
-
-                metadata = (match_off << 3) | 7;
-                out_buf.push(metadata as u8);
-                out_buf.push((metadata >> 8) as u8);
-                metadata_size += mem::size_of::<u16>();
-
-                match_len -= 7;
-
-                if nibble_index == 0 {
-                    nibble_index = out_idx;
-                    if match_len < 15 {
-                        out_buf.push(match_len as u8);
-                        metadata_size += mem::size_of::<u8>();
-                    } else {
-                        out_buf.push(15);
-                        metadata_size += mem::size_of::<u8>();
-
-                        has_extra_len = true;
-                    }
-                } else {
-                    if match_len < 15 {
-                        out_buf[nibble_index] |= (match_len << 4) as u8;
-                        nibble_index = 0;
-                    } else {
-                        out_buf[nibble_index] |= (15 << 4) as u8;
-                        nibble_index = 0;
+            encoder.copy(match_off, best_len);
+            in_idx += best_len;
+        }
+    }
 
-                        has_extra_len = true;
-                    }
-                }
+    Ok(encoder.finish(options.pad_ones))
+}
 
-                if has_extra_len {
-                    match_len -= 15;
+// Serializes the given tokens the way compress() does. Match offsets are not
+// checked against the output, invalid back-references can be crafted.
+pub fn encode_tokens(
+    tokens: &[TokenKind]
+) -> Result<Vec<u8>, Error>
+{
+    let mut encoder = Encoder::new();
 
-                    if match_len < 255 {
-                        out_buf.push(match_len as u8); 
-                        metadata_size += mem::size_of::<u8>();
-                    } else {
-                        out_buf.push(255);
-                        metadata_size += mem::size_of::<u8>();
-
-                        match_len += 7 + 15;
-
-                        if match_len < (1 << 16) {
-                            out_buf.push(match_len as u8);
-                            out_buf.push((match_len >> 8) as u8);
-                            metadata_size += mem::size_of::<u16>();
-                        } else {
-                            out_buf.push(0);
-                            out_buf.push(0);
-                            metadata_size += mem::size_of::<u16>();
-                            out_buf.push(match_len as u8);
-                            out_buf.push((match_len >> 8) as u8);
-                            out_buf.push((match_len >> 16) as u8);
-                            out_buf.push((match_len >> 24) as u8);
-                            metadata_size += mem::size_of::<u32>();
-                        }
-                    }
+    for token in tokens {
+        match *token {
+            TokenKind::Literal(b) => encoder.literal(b),
+            TokenKind::Match { offset, length } => {
+                if offset == 0 || offset > 8192 || length < 3 || (length - 3) > u32::MAX as usize {
+                    return Err(Error::CorruptedData);
                 }
-            }
-
-            flags = (flags << 1) | 1;
-            flag_count += 1;
-            if flag_count == 32 {
-                store32le!(out_buf, flag_out_off, flags);
-                flag_count = 0;
-                flag_out_off = out_idx;
-                out_buf.push(0);
-                out_buf.push(0);
-                out_buf.push(0);
-                out_buf.push(0);
-                out_idx += mem::size_of::<u32>();
-            }
 
-            out_idx += metadata_size;
-            in_idx += best_len;
+                encoder.copy(offset, length);
+            },
         }
     }
 
-    flags <<= 32 - flag_count;
-    flags |= (1 << (32 - flag_count)) - 1;
-    store32le!(out_buf, flag_out_off, flags);
-
-    Ok(out_buf)
-}
\ No newline at end of file
+    Ok(encoder.finish(true))
+}
@@ -0,0 +1,134 @@
+pub use crate::error::Error;
+
+use crate::data;
+
+// [MS-DRSR] DRS_COMPRESSED_BLOB: the compressed replies of
+// DRS_MSG_GETCHGREPLY_V2/V7 are a sequence of blocks, each preceded by
+// cbUncompressedSize and cbCompressedSize. A block whose sizes are equal
+// is stored uncompressed.
+pub const BLOCK_HEADER_SIZE: usize = 8;
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+// DRS_COMP_ALG_TYPE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    Mszip,
+    // DRS_COMP_ALG_WIN2K3: XPRESS (Plain LZ77)
+    Xpress,
+}
+
+impl CompressionAlgorithm {
+    pub fn from_u32(
+        value: u32
+    ) -> Result<CompressionAlgorithm, Error>
+    {
+        match value {
+            0 => Ok(CompressionAlgorithm::None),
+            2 => Ok(CompressionAlgorithm::Mszip),
+            3 => Ok(CompressionAlgorithm::Xpress),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedBlock<'a> {
+    pub uncompressed_size: u32,
+    pub compressed_size:   u32,
+    pub data:              &'a [u8],
+}
+
+impl CompressedBlock<'_> {
+    pub fn is_stored(&self) -> bool {
+        self.uncompressed_size == self.compressed_size
+    }
+
+    pub fn decompress(
+        &self,
+        algorithm: CompressionAlgorithm
+    ) -> Result<Vec<u8>, Error>
+    {
+        let out_len = self.uncompressed_size as usize;
+
+        if self.is_stored() {
+            return Ok(self.data.to_vec());
+        }
+
+        match algorithm {
+            CompressionAlgorithm::Xpress => data::decompress_exact(self.data, out_len),
+            CompressionAlgorithm::None => Err(Error::CorruptedData),
+            CompressionAlgorithm::Mszip => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+}
+
+pub fn blocks(
+    in_buf: &[u8]
+) -> Result<Vec<CompressedBlock<'_>>, Error>
+{
+    let mut in_idx: usize = 0;
+    let mut blocks: Vec<CompressedBlock> = Vec::new();
+
+    while in_idx < in_buf.len() {
+        let uncompressed_size: usize;
+        let compressed_size:   usize;
+
+        if (in_buf.len() - in_idx) < BLOCK_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load32le!(uncompressed_size, in_buf, in_idx);
+        load32le!(compressed_size, in_buf, in_idx + 4);
+        in_idx += BLOCK_HEADER_SIZE;
+
+        if compressed_size > (in_buf.len() - in_idx) {
+            return Err(Error::MemLimit);
+        }
+
+        blocks.push(CompressedBlock {
+            uncompressed_size: uncompressed_size as u32,
+            compressed_size: compressed_size as u32,
+            data: &in_buf[in_idx..in_idx + compressed_size],
+        });
+
+        in_idx += compressed_size;
+    }
+
+    Ok(blocks)
+}
+
+// uncompressed_size is the cbUncompressedSize of the DRS_COMPRESSED_BLOB.
+pub fn decompress(
+    in_buf: &[u8],
+    algorithm: CompressionAlgorithm,
+    uncompressed_size: usize
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    for block in blocks(in_buf)? {
+        if block.uncompressed_size as usize > (uncompressed_size - out_buf.len()) {
+            return Err(Error::MemLimit);
+        }
+
+        let uncompressed = block.decompress(algorithm)?;
+        out_buf.extend_from_slice(&uncompressed);
+    }
+
+    if out_buf.len() != uncompressed_size {
+        return Err(Error::CorruptedData);
+    }
+
+    Ok(out_buf)
+}
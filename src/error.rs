@@ -1,10 +1,18 @@
 // An error produced by an operation on LZXpress data
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     // Memory limit would be violated
     MemLimit,
     // Corrupt data
     CorruptedData,
+    // Unexpected signature or magic value
+    InvalidSignature,
+    // Checksum does not match the data
+    ChecksumMismatch,
+    // Compression algorithm not implemented by this crate
+    UnsupportedAlgorithm,
+    // Option or argument out of its accepted range
+    InvalidParameter,
     // An unknown error
     Other,
 }
\ No newline at end of file
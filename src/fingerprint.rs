@@ -0,0 +1,340 @@
+use std::cmp;
+use std::mem;
+
+pub use crate::error::Error;
+
+use crate::data::{self, CompressOptions, Provenance, Source};
+use crate::lznt1;
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    // data::compress and lznt1::compress
+    Crate,
+    // lib/compression/lzxpress.c, Plain LZ77 only
+    Samba,
+    // RtlCompressBuffer with COMPRESSION_ENGINE_STANDARD, LZNT1 only. Not
+    // calibrated against ntdll output: a first-hit match search standing in
+    // for its hash chains, see is_heuristic.
+    Ntdll,
+}
+
+impl Encoder {
+    // The model replays a guess at the encoder rather than its actual match
+    // search, its scores only tell it apart from the other models. Report::best
+    // never names it.
+    pub fn is_heuristic(&self) -> bool {
+        matches!(self, Encoder::Ntdll)
+    }
+}
+
+// Unused bits of the last flag word of a Plain LZ77 stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Ones,
+    Zeros,
+    Mixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub encoder:   Encoder,
+    // Share of the token choices (and of the flag padding) the encoder
+    // would have made the same way, 0.0 to 1.0. Only the choices where the
+    // encoders do not all agree are counted.
+    pub agreement: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub literals:       usize,
+    pub matches:        usize,
+    pub longest_match:  usize,
+    pub farthest_match: usize,
+    // Plain LZ77 only, None when the last flag word is full
+    pub padding:        Option<Padding>,
+    // Highest agreement first
+    pub scores:         Vec<Score>,
+}
+
+impl Report {
+    // None when the stream does not tell the best candidates apart. A
+    // heuristic model is never named: when it scores at least as well as
+    // the others, there is no answer either.
+    pub fn best(&self) -> Option<Encoder> {
+        match self.scores.as_slice() {
+            [first, ..] if first.encoder.is_heuristic() => None,
+            [first, second, ..] if first.agreement <= second.agreement => None,
+            [first, ..] if first.agreement > 0.0 => Some(first.encoder),
+            _ => None,
+        }
+    }
+
+    fn record(
+        &mut self,
+        chosen: Option<(usize, usize)>
+    )
+    {
+        match chosen {
+            None => self.literals += 1,
+            Some((offset, length)) => {
+                self.matches += 1;
+                self.longest_match = cmp::max(self.longest_match, length);
+                self.farthest_match = cmp::max(self.farthest_match, offset);
+            },
+        }
+    }
+
+    fn push_score(
+        &mut self,
+        encoder: Encoder,
+        agreed: usize,
+        decisions: usize
+    )
+    {
+        let agreement = if decisions == 0 { 0.0 } else { agreed as f32 / decisions as f32 };
+
+        self.scores.push(Score { encoder, agreement });
+        self.scores.sort_by(|a, b| b.agreement.total_cmp(&a.agreement));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Search {
+    // Longest match of the window, the closest one wins ties
+    Longest,
+    // Closest position sharing the next 3 bytes, extended as far as it goes.
+    // Approximates the hash lookups of the ntdll standard engine.
+    FirstHit,
+}
+
+// LZNT1 encoders, the limits come from the copy token layout
+const LZNT1_ENCODERS: [(Encoder, Search); 2] = [
+    (Encoder::Crate, Search::Longest),
+    (Encoder::Ntdll, Search::FirstHit),
+];
+
+// Token the search would emit at pos: (offset, length), None for a literal.
+fn pick(
+    window: &[u8],
+    pos: usize,
+    max_off: usize,
+    max_len: usize,
+    search: Search
+) -> Option<(usize, usize)>
+{
+    let max_len = cmp::min(max_len, window.len() - pos);
+
+    let mut best_len: usize = 2;
+    let mut best_off: usize = 0;
+
+    for offset in 1..=cmp::min(max_off, pos) {
+        let mut len = 0;
+        while len < max_len && window[pos + len] == window[pos - offset + len] {
+            len += 1;
+        }
+
+        if len > best_len {
+            best_len = len;
+            best_off = offset;
+            if search == Search::FirstHit || len == max_len {
+                break;
+            }
+        }
+    }
+
+    if best_off == 0 {
+        None
+    } else {
+        Some((best_off, best_len))
+    }
+}
+
+// Output offset and choice of every token of the provenance map.
+fn decisions(
+    provenance: &Provenance
+) -> Vec<(usize, Option<(usize, usize)>)>
+{
+    let mut decisions = Vec::new();
+
+    for run in provenance.runs() {
+        match run.source {
+            Source::Literal { .. } => {
+                decisions.extend((run.out_idx..run.out_idx + run.len).map(|out_idx| (out_idx, None)));
+            },
+            Source::Match { src_out_idx, .. } => {
+                decisions.push((run.out_idx, Some((run.out_idx - src_out_idx, run.len))));
+            },
+        }
+    }
+
+    decisions
+}
+
+// Number of decisions each model would have made the same way, out of the
+// decisions where the models do not all pick the same token: those do not
+// tell them apart. model(i, pos) gives the token of model i at pos.
+fn agreement(
+    decisions: &[(usize, Option<(usize, usize)>)],
+    model_count: usize,
+    model: impl Fn(usize, usize) -> Option<(usize, usize)>
+) -> (Vec<usize>, usize)
+{
+    let mut agreed = vec![0usize; model_count];
+    let mut total: usize = 0;
+
+    for &(pos, chosen) in decisions.iter() {
+        let picks: Vec<Option<(usize, usize)>> = (0..model_count).map(|i| model(i, pos)).collect();
+
+        if picks.iter().all(|pick| *pick == picks[0]) {
+            continue;
+        }
+
+        total += 1;
+        for (i, pick) in picks.iter().enumerate() {
+            if *pick == chosen {
+                agreed[i] += 1;
+            }
+        }
+    }
+
+    (agreed, total)
+}
+
+// Unused bits of the flag word in effect at the end of the stream.
+fn padding(
+    in_buf: &[u8]
+) -> Result<Option<Padding>, Error>
+{
+    let mut tokens = data::tokens(in_buf);
+    let mut last: Option<data::Token> = None;
+    let mut last_end: usize = 0;
+    let flags: usize;
+    let mask: usize;
+
+    while let Some(token) = tokens.next() {
+        last = Some(token?);
+        last_end = tokens.position();
+    }
+
+    let last = match last {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+
+    if tokens.position() > last_end {
+        // The last token filled its flag word, a fresh one follows
+        load32le!(flags, in_buf, tokens.position() - mem::size_of::<u32>());
+        mask = 0xFFFF_FFFF;
+    } else if last.flag_bit > 0 {
+        load32le!(flags, in_buf, last.flag_idx);
+        mask = (1 << last.flag_bit) - 1;
+    } else {
+        return Ok(None);
+    }
+
+    Ok(Some(match flags & mask {
+        0 => Padding::Zeros,
+        bits if bits == mask => Padding::Ones,
+        _ => Padding::Mixed,
+    }))
+}
+
+// Scores a Plain LZ77 stream against the known encoders.
+pub fn analyze_data(
+    in_buf: &[u8]
+) -> Result<Report, Error>
+{
+    let (window, provenance) = data::decompress_with_provenance(in_buf)?;
+    let decisions = decisions(&provenance);
+    let mut report = Report { padding: padding(in_buf)?, ..Report::default() };
+
+    for &(_, chosen) in decisions.iter() {
+        report.record(chosen);
+    }
+
+    let encoders = [
+        (Encoder::Crate, CompressOptions::default()),
+        (Encoder::Samba, CompressOptions::samba()),
+    ];
+
+    let (agreed, total) = agreement(&decisions, encoders.len(), |i, pos| {
+        let (_, options) = encoders[i];
+        pick(&window, pos, options.max_offset, options.max_length, Search::Longest)
+    });
+
+    for (i, &(encoder, options)) in encoders.iter().enumerate() {
+        let mut agreed = agreed[i];
+        let mut total = total;
+
+        if let Some(padding) = report.padding {
+            let expected = if options.pad_ones { Padding::Ones } else { Padding::Zeros };
+
+            total += 1;
+            if padding == expected {
+                agreed += 1;
+            }
+        }
+
+        report.push_score(encoder, agreed, total);
+    }
+
+    Ok(report)
+}
+
+// Scores an LZNT1 stream against the known encoders. Stored chunks do not
+// say anything about the match selection and are skipped.
+pub fn analyze_lznt1(
+    in_buf: &[u8]
+) -> Result<Report, Error>
+{
+    let mut report = Report::default();
+    let mut agreed = [0usize; LZNT1_ENCODERS.len()];
+    let mut total: usize = 0;
+
+    for chunk in lznt1::chunks(in_buf) {
+        let chunk = chunk?;
+
+        if !chunk.compressed {
+            continue;
+        }
+
+        let chunk_end = chunk.offset + mem::size_of::<u16>() + chunk.size;
+        let (window, provenance) = lznt1::decompress_with_provenance(&in_buf[chunk.offset..chunk_end])?;
+        let decisions = decisions(&provenance);
+
+        for &(_, chosen) in decisions.iter() {
+            report.record(chosen);
+        }
+
+        let (chunk_agreed, chunk_total) = agreement(&decisions, LZNT1_ENCODERS.len(), |i, pos| {
+            if pos == 0 {
+                return None;
+            }
+
+            let (_, search) = LZNT1_ENCODERS[i];
+            let (max_len, max_off, _) = lznt1::copy_token_limits(pos);
+            pick(&window, pos, max_off, max_len, search)
+        });
+        for (i, n) in chunk_agreed.iter().enumerate() {
+            agreed[i] += n;
+        }
+        total += chunk_total;
+    }
+
+    for (i, &(encoder, _)) in LZNT1_ENCODERS.iter().enumerate() {
+        report.push_score(encoder, agreed[i], total);
+    }
+
+    Ok(report)
+}
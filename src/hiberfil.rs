@@ -0,0 +1,534 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub use crate::error::Error;
+
+use crate::data;
+use crate::huffman;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+// Windows 7: "\x81\x81xpress" blocks of up to 16 pages, Plain LZ77
+const XPRESS_MAGIC:       &[u8] = b"\x81\x81xpress";
+const XPRESS_HEADER_SIZE: usize = 0x20;
+const XPRESS_MAX_PAGES:   usize = 16;
+
+// PO_MEMORY_IMAGE (x64)
+const HEADER_SIGNATURE:                 usize = 0x00;
+const HEADER_LENGTH_SELF:               usize = 0x0c;
+const HEADER_PAGE_SIZE:                 usize = 0x18;
+const HEADER_NUM_PAGES_FOR_LOADER:      usize = 0x58;
+// FirstTablePage on Windows 7, FirstBootRestorePage on Windows 8+
+const HEADER_FIRST_TABLE_PAGE:          usize = 0x68;
+const HEADER_FIRST_KERNEL_RESTORE_PAGE: usize = 0x70;
+const HEADER_KERNEL_PAGES_PROCESSED:    usize = 0x230;
+const HEADER_SIZE:                      usize = 0x238;
+
+// PO_MEMORY_RANGE_ARRAY (x64): a link followed by { PageNo, StartPage, EndPage, CheckSum }
+const RANGE_ARRAY_NEXT_TABLE:  usize = 0x08;
+const RANGE_ARRAY_ENTRY_COUNT: usize = 0x14;
+const RANGE_ARRAY_ENTRIES:     usize = 0x20;
+const RANGE_ENTRY_SIZE:        usize = 0x20;
+const RANGE_ENTRY_START_PAGE:  usize = 0x08;
+const RANGE_ENTRY_END_PAGE:    usize = 0x10;
+
+// Windows 8+ compression set header:
+//   bits 0..7   number of page run descriptors
+//   bits 8..29  compressed size
+//   bit  31     XPRESS Huffman instead of Plain LZ77
+// Each descriptor is a u64: bits 0..3 page count - 1, bits 4..63 first page frame number.
+const SET_HEADER_SIZE:     usize = 4;
+const SET_DESCRIPTOR_SIZE: usize = 8;
+const SET_MAX_PAGES:       usize = 16;
+
+const SIGNATURES: [&[u8; 4]; 6] = [b"hibr", b"HIBR", b"wake", b"WAKE", b"rstr", b"RSTR"];
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+macro_rules! load64le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&$src[$idx..$idx + 8]);
+            $dst = u64::from_le_bytes(bytes);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // PO_MEMORY_RANGE_ARRAY tables followed by xpress blocks
+    Win7,
+    // Boot and kernel restoration sets made of compression sets
+    Win8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xpress,
+    XpressHuffman,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryImageHeader {
+    pub signature:                 [u8; 4],
+    pub length_self:               u32,
+    pub page_size:                 u32,
+    pub num_pages_for_loader:      u64,
+    pub first_table_page:          u64,
+    pub first_kernel_restore_page: u64,
+    pub kernel_pages_processed:    u64,
+}
+
+impl MemoryImageHeader {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<MemoryImageHeader, Error>
+    {
+        let length_self:    usize;
+        let page_size:      usize;
+
+        let num_pages_for_loader:      u64;
+        let first_table_page:          u64;
+        let first_kernel_restore_page: u64;
+        let kernel_pages_processed:    u64;
+
+        if in_buf.len() < HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(&in_buf[HEADER_SIGNATURE..HEADER_SIGNATURE + 4]);
+        if !SIGNATURES.iter().any(|s| **s == signature) {
+            return Err(Error::InvalidSignature);
+        }
+
+        load32le!(length_self, in_buf, HEADER_LENGTH_SELF);
+        load32le!(page_size, in_buf, HEADER_PAGE_SIZE);
+        if page_size != PAGE_SIZE {
+            return Err(Error::CorruptedData);
+        }
+
+        load64le!(num_pages_for_loader, in_buf, HEADER_NUM_PAGES_FOR_LOADER);
+        load64le!(first_table_page, in_buf, HEADER_FIRST_TABLE_PAGE);
+        load64le!(first_kernel_restore_page, in_buf, HEADER_FIRST_KERNEL_RESTORE_PAGE);
+        load64le!(kernel_pages_processed, in_buf, HEADER_KERNEL_PAGES_PROCESSED);
+
+        Ok(MemoryImageHeader {
+            signature,
+            length_self: length_self as u32,
+            page_size: page_size as u32,
+            num_pages_for_loader,
+            first_table_page,
+            first_kernel_restore_page,
+            kernel_pages_processed,
+        })
+    }
+}
+
+// A run of up to 16 pages compressed together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageSet {
+    // Offset of the (compressed) data in the hibernation file
+    pub offset:      u64,
+    pub size:        usize,
+    pub pages:       usize,
+    pub compression: Compression,
+}
+
+impl PageSet {
+    pub fn decompress(
+        &self,
+        in_buf: &[u8]
+    ) -> Result<Vec<u8>, Error>
+    {
+        let out_len = self.pages * PAGE_SIZE;
+
+        let out_buf = match self.compression {
+            Compression::None => {
+                if in_buf.len() < out_len {
+                    return Err(Error::MemLimit);
+                }
+                in_buf[..out_len].to_vec()
+            },
+            Compression::Xpress => data::decompress_bounded(in_buf, out_len)?,
+            Compression::XpressHuffman => huffman::decompress(in_buf, out_len)?,
+        };
+
+        if out_buf.len() != out_len {
+            return Err(Error::CorruptedData);
+        }
+
+        Ok(out_buf)
+    }
+}
+
+// Physical memory stored in a hibernation file, as a sparse Read + Seek
+// stream. Pages missing from the file read as zeroes.
+pub struct HiberFile<R> {
+    reader:   R,
+    header:   MemoryImageHeader,
+    layout:   Layout,
+    sets:     Vec<PageSet>,
+    // page frame number -> (page set, page index in the set)
+    pages:    BTreeMap<u64, (usize, usize)>,
+    cache:    Option<(usize, Vec<u8>)>,
+    position: u64,
+}
+
+fn invalid_data(
+    e: Error
+) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+// Header values are not trusted, offsets that do not fit in a u64 are corrupted data.
+fn checked_offset(
+    offset: Option<u64>
+) -> io::Result<u64>
+{
+    offset.ok_or_else(|| invalid_data(Error::CorruptedData))
+}
+
+fn page_offset(
+    page: u64
+) -> io::Result<u64>
+{
+    checked_offset(page.checked_mul(PAGE_SIZE as u64))
+}
+
+fn read_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: usize
+) -> io::Result<Vec<u8>>
+{
+    let mut buf = vec![0u8; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+impl<R: Read + Seek> HiberFile<R> {
+    pub fn new(
+        mut reader: R
+    ) -> io::Result<HiberFile<R>>
+    {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let raw_header = read_at(&mut reader, 0, HEADER_SIZE)?;
+        let header = MemoryImageHeader::parse(&raw_header).map_err(invalid_data)?;
+
+        if header.first_table_page == 0 {
+            return Err(invalid_data(Error::CorruptedData));
+        }
+
+        // Windows 7 tables are immediately followed by an xpress block.
+        let probe_offset = page_offset(checked_offset(header.first_table_page.checked_add(1))?)?;
+        let layout = match read_at(&mut reader, probe_offset, XPRESS_MAGIC.len()) {
+            Ok(magic) if magic == XPRESS_MAGIC => Layout::Win7,
+            _ => Layout::Win8,
+        };
+
+        let mut hiberfil = HiberFile {
+            reader,
+            header,
+            layout,
+            sets: Vec::new(),
+            pages: BTreeMap::new(),
+            cache: None,
+            position: 0,
+        };
+
+        match layout {
+            Layout::Win7 => hiberfil.walk_tables(file_len)?,
+            Layout::Win8 => {
+                let first_page = hiberfil.header.first_table_page;
+                let total_pages = hiberfil.header.num_pages_for_loader;
+                hiberfil.walk_restore_set(first_page, total_pages)?;
+
+                let first_page = hiberfil.header.first_kernel_restore_page;
+                let total_pages = hiberfil.header.kernel_pages_processed;
+                if first_page != 0 {
+                    hiberfil.walk_restore_set(first_page, total_pages)?;
+                }
+            },
+        }
+
+        Ok(hiberfil)
+    }
+
+    fn walk_tables(
+        &mut self,
+        file_len: u64
+    ) -> io::Result<()>
+    {
+        let max_entries = (PAGE_SIZE - RANGE_ARRAY_ENTRIES) / RANGE_ENTRY_SIZE;
+        let max_tables = file_len / PAGE_SIZE as u64;
+
+        let mut table_page = self.header.first_table_page;
+        let mut table_count = 0;
+
+        while table_page != 0 {
+            let next_table:  u64;
+            let entry_count: usize;
+
+            table_count += 1;
+            if table_count > max_tables {
+                return Err(invalid_data(Error::CorruptedData));
+            }
+
+            let table = read_at(&mut self.reader, page_offset(table_page)?, PAGE_SIZE)?;
+            load64le!(next_table, table, RANGE_ARRAY_NEXT_TABLE);
+            load32le!(entry_count, table, RANGE_ARRAY_ENTRY_COUNT);
+
+            if entry_count > max_entries {
+                return Err(invalid_data(Error::CorruptedData));
+            }
+
+            let mut block_offset = page_offset(checked_offset(table_page.checked_add(1))?)?;
+            let mut block_page = XPRESS_MAX_PAGES;
+
+            for i in 0..entry_count {
+                let start_page: u64;
+                let end_page:   u64;
+
+                let entry = RANGE_ARRAY_ENTRIES + i * RANGE_ENTRY_SIZE;
+                load64le!(start_page, table, entry + RANGE_ENTRY_START_PAGE);
+                load64le!(end_page, table, entry + RANGE_ENTRY_END_PAGE);
+
+                // Keeps the end of every page within the address space.
+                page_offset(end_page)?;
+
+                for pfn in start_page..end_page {
+                    if block_page >= self.sets.last().map_or(0, |set| set.pages) {
+                        block_offset = self.read_xpress_header(block_offset)?;
+                        block_page = 0;
+                    }
+
+                    self.pages.insert(pfn, (self.sets.len() - 1, block_page));
+                    block_page += 1;
+                }
+            }
+
+            table_page = next_table;
+        }
+
+        Ok(())
+    }
+
+    // Records the xpress block at offset and returns the offset of the next one.
+    fn read_xpress_header(
+        &mut self,
+        offset: u64
+    ) -> io::Result<u64>
+    {
+        let block_header = read_at(&mut self.reader, offset, XPRESS_HEADER_SIZE)?;
+        if &block_header[..XPRESS_MAGIC.len()] != XPRESS_MAGIC {
+            return Err(invalid_data(Error::InvalidSignature));
+        }
+
+        let pages = usize::from(block_header[8]) + 1;
+        if pages > XPRESS_MAX_PAGES {
+            return Err(invalid_data(Error::CorruptedData));
+        }
+
+        let mut size = ((usize::from(block_header[11]) << 24)
+            | (usize::from(block_header[10]) << 16)
+            | (usize::from(block_header[9]) << 8)) >> 10;
+        size += 1;
+        // Blocks are 8 bytes aligned.
+        size = (size + 7) & !7;
+
+        let compression = if size == pages * PAGE_SIZE {
+            Compression::None
+        } else {
+            Compression::Xpress
+        };
+
+        self.sets.push(PageSet {
+            offset: offset + XPRESS_HEADER_SIZE as u64,
+            size,
+            pages,
+            compression,
+        });
+
+        checked_offset(offset.checked_add((XPRESS_HEADER_SIZE + size) as u64))
+    }
+
+    fn walk_restore_set(
+        &mut self,
+        first_page: u64,
+        total_pages: u64
+    ) -> io::Result<()>
+    {
+        let mut offset = page_offset(first_page)?;
+        let mut processed: u64 = 0;
+
+        while processed < total_pages {
+            let set_header:  usize;
+
+            let raw = read_at(&mut self.reader, offset, SET_HEADER_SIZE)?;
+            load32le!(set_header, raw, 0);
+
+            let descriptor_count = set_header & 0xff;
+            let size = (set_header >> 8) & 0x3f_ffff;
+            let is_huffman = (set_header >> 31) != 0;
+
+            if descriptor_count == 0 || descriptor_count > SET_MAX_PAGES {
+                return Err(invalid_data(Error::CorruptedData));
+            }
+
+            offset = checked_offset(offset.checked_add(SET_HEADER_SIZE as u64))?;
+            let descriptors = read_at(&mut self.reader, offset, descriptor_count * SET_DESCRIPTOR_SIZE)?;
+            offset = checked_offset(offset.checked_add(descriptors.len() as u64))?;
+
+            let mut pfns: Vec<u64> = Vec::with_capacity(SET_MAX_PAGES);
+            for i in 0..descriptor_count {
+                let descriptor: u64;
+                load64le!(descriptor, descriptors, i * SET_DESCRIPTOR_SIZE);
+
+                let count = (descriptor & 0xf) + 1;
+                let pfn = descriptor >> 4;
+                page_offset(checked_offset(pfn.checked_add(count))?)?;
+                for n in 0..count {
+                    pfns.push(pfn + n);
+                }
+            }
+
+            if pfns.len() > SET_MAX_PAGES {
+                return Err(invalid_data(Error::CorruptedData));
+            }
+
+            let compression = if size == pfns.len() * PAGE_SIZE {
+                Compression::None
+            } else if is_huffman {
+                Compression::XpressHuffman
+            } else {
+                Compression::Xpress
+            };
+
+            let set_idx = self.sets.len();
+            self.sets.push(PageSet {
+                offset,
+                size,
+                pages: pfns.len(),
+                compression,
+            });
+
+            for (page, pfn) in pfns.iter().enumerate() {
+                self.pages.insert(*pfn, (set_idx, page));
+            }
+
+            offset = checked_offset(offset.checked_add(size as u64))?;
+            processed += pfns.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    pub fn header(&self) -> &MemoryImageHeader {
+        &self.header
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn page_sets(&self) -> &[PageSet] {
+        &self.sets
+    }
+
+    // Page frame numbers present in the file, in ascending order.
+    pub fn page_frames(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pages.keys().copied()
+    }
+
+    // Size of the physical address space, up to the highest page present.
+    // The page frames were checked against overflow when the file was parsed.
+    pub fn len(&self) -> u64 {
+        self.pages.keys().next_back().map_or(0, |pfn| (pfn + 1) * PAGE_SIZE as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    fn load_set(
+        &mut self,
+        set_idx: usize
+    ) -> io::Result<&[u8]>
+    {
+        let cached = matches!(self.cache, Some((idx, _)) if idx == set_idx);
+
+        if !cached {
+            let set = &self.sets[set_idx];
+            let raw = read_at(&mut self.reader, set.offset, set.size)?;
+            let out_buf = set.decompress(&raw).map_err(invalid_data)?;
+            self.cache = Some((set_idx, out_buf));
+        }
+
+        match &self.cache {
+            Some((_, out_buf)) => Ok(out_buf),
+            None => Err(invalid_data(Error::Other)),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for HiberFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.len();
+        if self.position >= len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let pfn = self.position / PAGE_SIZE as u64;
+        let page_off = (self.position % PAGE_SIZE as u64) as usize;
+        let count = cmp::min(buf.len(), PAGE_SIZE - page_off);
+
+        match self.pages.get(&pfn).copied() {
+            Some((set_idx, page)) => {
+                let set_data = self.load_set(set_idx)?;
+                let start = page * PAGE_SIZE + page_off;
+                buf[..count].copy_from_slice(&set_data[start..start + count]);
+            },
+            None => {
+                for b in buf[..count].iter_mut() {
+                    *b = 0;
+                }
+            },
+        }
+
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Read + Seek> Seek for HiberFile<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position")),
+        }
+    }
+}
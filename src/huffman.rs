@@ -0,0 +1,551 @@
+use std::mem;
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+
+pub use crate::error::Error;
+
+// [MS-XCA] 2.2: LZ77+Huffman
+const HUFFMAN_TABLE_SIZE:   usize = 256;
+const HUFFMAN_SYMBOLS:      usize = 512;
+const HUFFMAN_MAX_BITS:     usize = 15;
+const HUFFMAN_BLOCK_SIZE:   usize = 65536;
+const HUFFMAN_EOF_SYMBOL:   usize = 256;
+
+const MATCH_MIN_LEN:        usize = 3;
+const MATCH_MAX_LEN:        usize = 0xFFFF + 3;
+const MATCH_MAX_OFFSET:     usize = 0xFFFF;
+const HASH_BITS:            usize = 15;
+const HASH_CHAIN_DEPTH:     usize = 64;
+
+macro_rules! load16le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = (u32::from($src[$idx + 1]) << 8
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+// Reads the next 16 bits of the bit stream. The word following the input
+// reads as zeroes, the encoder does not always pad the last word. Reading
+// any further means the stream is truncated.
+fn read16(
+    in_buf: &[u8],
+    in_idx: usize
+) -> Result<u32, Error>
+{
+    let word: usize;
+
+    if (in_idx + 1) >= in_buf.len() {
+        if in_idx > in_buf.len() {
+            return Err(Error::CorruptedData);
+        }
+
+        return Ok(0);
+    }
+
+    load16le!(word, in_buf, in_idx);
+    Ok(word as u32)
+}
+
+// Builds the 2^15 entries decoding table from the 256 bytes table of
+// 4-bit symbol lengths starting at in_idx.
+fn build_decoding_table(
+    in_buf: &[u8],
+    in_idx: usize,
+    lengths: &mut [u8; HUFFMAN_SYMBOLS],
+    table: &mut [u16]
+) -> Result<(), Error>
+{
+    if (in_idx + HUFFMAN_TABLE_SIZE) > in_buf.len() {
+        return Err(Error::CorruptedData);
+    }
+
+    for i in 0..HUFFMAN_TABLE_SIZE {
+        lengths[2 * i] = in_buf[in_idx + i] & 0xf;
+        lengths[2 * i + 1] = in_buf[in_idx + i] >> 4;
+    }
+
+    let mut entry: usize = 0;
+    for bit_len in 1..=HUFFMAN_MAX_BITS {
+        for (symbol, length) in lengths.iter().enumerate() {
+            if usize::from(*length) != bit_len {
+                continue;
+            }
+
+            let count = 1 << (HUFFMAN_MAX_BITS - bit_len);
+            if (entry + count) > table.len() {
+                return Err(Error::CorruptedData);
+            }
+
+            for slot in table[entry..entry + count].iter_mut() {
+                *slot = symbol as u16;
+            }
+            entry += count;
+        }
+    }
+
+    if entry != table.len() {
+        return Err(Error::CorruptedData);
+    }
+
+    Ok(())
+}
+
+// LZ77+Huffman streams do not carry their decompressed size, the caller
+// has to provide it (out_len). The output is shorter when the stream ends
+// first.
+pub fn decompress(
+    in_buf: &[u8],
+    out_len: usize
+) -> Result<Vec<u8>, Error>
+{
+    let mut in_idx:  usize = 0;
+
+    let mut lengths = [0u8; HUFFMAN_SYMBOLS];
+    let mut table = vec![0u16; 1 << HUFFMAN_MAX_BITS];
+
+    // out_len is not trusted, every block starts with its own table.
+    let max_blocks = in_buf.len() / HUFFMAN_TABLE_SIZE + 1;
+    let mut out_buf: Vec<u8> = Vec::with_capacity(cmp::min(out_len, max_blocks * HUFFMAN_BLOCK_SIZE));
+
+    while out_buf.len() < out_len {
+        build_decoding_table(in_buf, in_idx, &mut lengths, &mut table)?;
+        in_idx += HUFFMAN_TABLE_SIZE;
+
+        let mut next_bits: u32 = read16(in_buf, in_idx)? << 16;
+        in_idx += mem::size_of::<u16>();
+        next_bits |= read16(in_buf, in_idx)?;
+        in_idx += mem::size_of::<u16>();
+        let mut extra_bits: i32 = 16;
+
+        let block_end = out_buf.len() + HUFFMAN_BLOCK_SIZE;
+
+        while out_buf.len() < block_end && out_buf.len() < out_len {
+            let symbol = usize::from(table[(next_bits >> (32 - HUFFMAN_MAX_BITS)) as usize]);
+            let bit_len = lengths[symbol];
+
+            next_bits <<= bit_len;
+            extra_bits -= i32::from(bit_len);
+            if extra_bits < 0 {
+                next_bits |= read16(in_buf, in_idx)? << (-extra_bits);
+                extra_bits += 16;
+                in_idx += mem::size_of::<u16>();
+            }
+
+            if symbol < 256 {
+                out_buf.push(symbol as u8);
+                continue;
+            }
+
+            // [MS-XCA] 2.2.4: the EOF symbol with the whole input read ends
+            // the stream, out_len may be larger than the data.
+            if symbol == HUFFMAN_EOF_SYMBOL && (in_idx + mem::size_of::<u16>()) >= in_buf.len() {
+                return Ok(out_buf);
+            }
+
+            let mut length = (symbol - 256) % 16;
+            let offset_bits = (symbol - 256) / 16;
+
+            if length == 15 {
+                if in_idx >= in_buf.len() {
+                    return Err(Error::CorruptedData);
+                }
+
+                length = in_buf[in_idx].into();
+                in_idx += mem::size_of::<u8>();
+
+                if length == 255 {
+                    if (in_idx + 1) >= in_buf.len() {
+                        return Err(Error::CorruptedData);
+                    }
+
+                    load16le!(length, in_buf, in_idx);
+                    in_idx += mem::size_of::<u16>();
+
+                    if length < 15 {
+                        return Err(Error::CorruptedData);
+                    }
+                    length -= 15;
+                }
+                length += 15;
+            }
+            length += 3;
+
+            let mut offset: usize = 1 << offset_bits;
+            if offset_bits > 0 {
+                offset += (next_bits >> (32 - offset_bits)) as usize;
+                next_bits <<= offset_bits;
+                extra_bits -= offset_bits as i32;
+                if extra_bits < 0 {
+                    next_bits |= read16(in_buf, in_idx)? << (-extra_bits);
+                    extra_bits += 16;
+                    in_idx += mem::size_of::<u16>();
+                }
+            }
+
+            if offset > out_buf.len() {
+                return Err(Error::CorruptedData);
+            }
+
+            for _i in 0..length {
+                if out_buf.len() >= out_len {
+                    break;
+                }
+                out_buf.push(out_buf[out_buf.len() - offset]);
+            }
+        }
+    }
+
+    Ok(out_buf)
+}
+
+enum Token {
+    Literal(u8),
+    Match(usize, usize),
+}
+
+fn hash3(
+    in_buf: &[u8],
+    idx: usize
+) -> usize
+{
+    let value = (u32::from(in_buf[idx]) << 16)
+        | (u32::from(in_buf[idx + 1]) << 8)
+        | u32::from(in_buf[idx + 2]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// LZ77 pass over in_buf[start..end], back-references may reach before start.
+fn find_tokens(
+    in_buf: &[u8],
+    start: usize,
+    end: usize,
+    head: &mut [usize],
+    prev: &mut [usize],
+    tokens: &mut Vec<Token>
+)
+{
+    let mut in_idx = start;
+
+    while in_idx < end {
+        let mut best_len: usize = 0;
+        let mut best_off: usize = 0;
+
+        if (in_idx + MATCH_MIN_LEN) <= in_buf.len() {
+            let max_len = cmp::min(MATCH_MAX_LEN, end - in_idx);
+            let mut candidate = head[hash3(in_buf, in_idx)];
+
+            for _i in 0..HASH_CHAIN_DEPTH {
+                if candidate == usize::MAX || (in_idx - candidate) > MATCH_MAX_OFFSET {
+                    break;
+                }
+
+                let mut len = 0;
+                while len < max_len && in_buf[candidate + len] == in_buf[in_idx + len] {
+                    len += 1;
+                }
+
+                if len > best_len {
+                    best_len = len;
+                    best_off = in_idx - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+
+                candidate = prev[candidate];
+            }
+        }
+
+        let token_len = if best_len >= MATCH_MIN_LEN {
+            tokens.push(Token::Match(best_len, best_off));
+            best_len
+        } else {
+            tokens.push(Token::Literal(in_buf[in_idx]));
+            1
+        };
+
+        for (idx, link) in prev.iter_mut().enumerate().skip(in_idx).take(token_len) {
+            if (idx + MATCH_MIN_LEN) <= in_buf.len() {
+                let hash = hash3(in_buf, idx);
+                *link = head[hash];
+                head[hash] = idx;
+            }
+        }
+
+        in_idx += token_len;
+    }
+}
+
+fn high_bit(
+    value: usize
+) -> usize
+{
+    (usize::BITS - 1 - value.leading_zeros()) as usize
+}
+
+fn match_symbol(
+    length: usize,
+    offset: usize
+) -> usize
+{
+    256 + cmp::min(length - MATCH_MIN_LEN, 15) + 16 * high_bit(offset)
+}
+
+// Huffman code lengths limited to 15 bits. Frequencies are halved until the
+// tree is shallow enough.
+fn build_lengths(
+    freqs: &[usize; HUFFMAN_SYMBOLS]
+) -> [u8; HUFFMAN_SYMBOLS]
+{
+    let mut weights = *freqs;
+
+    // The decoding table must be complete, which needs two symbols at least.
+    if weights.iter().filter(|w| **w > 0).count() < 2 {
+        let unused = if weights[0] == 0 { 0 } else { 1 };
+        weights[unused] = 1;
+    }
+
+    loop {
+        let mut lengths = [0u8; HUFFMAN_SYMBOLS];
+        let mut parent: Vec<usize> = vec![usize::MAX; 2 * HUFFMAN_SYMBOLS];
+        let mut heap = BinaryHeap::new();
+
+        for (symbol, weight) in weights.iter().enumerate() {
+            if *weight > 0 {
+                heap.push(Reverse((*weight, symbol)));
+            }
+        }
+
+        let mut node = HUFFMAN_SYMBOLS;
+        while heap.len() > 1 {
+            let Reverse((weight1, node1)) = heap.pop().unwrap();
+            let Reverse((weight2, node2)) = heap.pop().unwrap();
+            parent[node1] = node;
+            parent[node2] = node;
+            heap.push(Reverse((weight1 + weight2, node)));
+            node += 1;
+        }
+
+        let mut max_len = 0;
+        for symbol in 0..HUFFMAN_SYMBOLS {
+            if weights[symbol] == 0 {
+                continue;
+            }
+
+            let mut len = 0;
+            let mut n = symbol;
+            while parent[n] != usize::MAX {
+                n = parent[n];
+                len += 1;
+            }
+            lengths[symbol] = len as u8;
+            max_len = cmp::max(max_len, len);
+        }
+
+        if max_len <= HUFFMAN_MAX_BITS {
+            return lengths;
+        }
+
+        for weight in weights.iter_mut() {
+            if *weight > 0 {
+                *weight = weight.div_ceil(2);
+            }
+        }
+    }
+}
+
+// Canonical codes, in the order the decoder fills its table.
+fn build_codes(
+    lengths: &[u8; HUFFMAN_SYMBOLS]
+) -> [u16; HUFFMAN_SYMBOLS]
+{
+    let mut codes = [0u16; HUFFMAN_SYMBOLS];
+    let mut entry: usize = 0;
+
+    for bit_len in 1..=HUFFMAN_MAX_BITS {
+        for (symbol, length) in lengths.iter().enumerate() {
+            if usize::from(*length) == bit_len {
+                codes[symbol] = (entry >> (HUFFMAN_MAX_BITS - bit_len)) as u16;
+                entry += 1 << (HUFFMAN_MAX_BITS - bit_len);
+            }
+        }
+    }
+
+    codes
+}
+
+// Mirrors the decoder: a 16-bit word is read when the bits consumed so far
+// exceed what the words already read can hold, so its slot is reserved in the
+// output at that point, after any length byte written before.
+struct BitWriter {
+    slots:       Vec<usize>,
+    next_slot:   usize,
+    bit_count:   usize,
+    words:       usize,
+    acc:         u32,
+    acc_bits:    usize,
+}
+
+impl BitWriter {
+    fn new(
+        out_buf: &mut Vec<u8>
+    ) -> BitWriter
+    {
+        let mut writer = BitWriter {
+            slots: Vec::new(),
+            next_slot: 0,
+            bit_count: 0,
+            words: 0,
+            acc: 0,
+            acc_bits: 0,
+        };
+
+        writer.reserve(out_buf);
+        writer.reserve(out_buf);
+        writer
+    }
+
+    fn reserve(
+        &mut self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        self.slots.push(out_buf.len());
+        out_buf.push(0);
+        out_buf.push(0);
+        self.words += 1;
+    }
+
+    fn store(
+        &mut self,
+        out_buf: &mut [u8],
+        word: u32
+    )
+    {
+        let slot = self.slots[self.next_slot];
+        out_buf[slot] = word as u8;
+        out_buf[slot + 1] = (word >> 8) as u8;
+        self.next_slot += 1;
+    }
+
+    fn write_bits(
+        &mut self,
+        out_buf: &mut Vec<u8>,
+        bit_count: usize,
+        bits: u32
+    )
+    {
+        if bit_count == 0 {
+            return;
+        }
+
+        self.bit_count += bit_count;
+        if self.bit_count > 16 * (self.words - 1) {
+            self.reserve(out_buf);
+        }
+
+        self.acc = (self.acc << bit_count) | bits;
+        self.acc_bits += bit_count;
+
+        if self.acc_bits >= 16 {
+            self.acc_bits -= 16;
+            let word = (self.acc >> self.acc_bits) & 0xffff;
+            self.store(out_buf, word);
+        }
+    }
+
+    fn flush(
+        &mut self,
+        out_buf: &mut [u8]
+    )
+    {
+        if self.acc_bits > 0 {
+            let word = (self.acc << (16 - self.acc_bits)) & 0xffff;
+            self.store(out_buf, word);
+            self.acc_bits = 0;
+        }
+    }
+}
+
+pub fn compress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len() / 2 + HUFFMAN_TABLE_SIZE + 4);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; in_buf.len()];
+    let mut tokens: Vec<Token> = Vec::new();
+
+    let mut block_start: usize = 0;
+
+    loop {
+        let block_end = cmp::min(block_start + HUFFMAN_BLOCK_SIZE, in_buf.len());
+        let is_last = block_end == in_buf.len();
+
+        tokens.clear();
+        find_tokens(in_buf, block_start, block_end, &mut head, &mut prev, &mut tokens);
+
+        let mut freqs = [0usize; HUFFMAN_SYMBOLS];
+        for token in tokens.iter() {
+            match *token {
+                Token::Literal(b) => freqs[usize::from(b)] += 1,
+                Token::Match(length, offset) => freqs[match_symbol(length, offset)] += 1,
+            }
+        }
+        if is_last {
+            freqs[HUFFMAN_EOF_SYMBOL] += 1;
+        }
+
+        let lengths = build_lengths(&freqs);
+        let codes = build_codes(&lengths);
+
+        for i in 0..HUFFMAN_TABLE_SIZE {
+            out_buf.push(lengths[2 * i] | (lengths[2 * i + 1] << 4));
+        }
+
+        let mut writer = BitWriter::new(&mut out_buf);
+
+        for token in tokens.iter() {
+            match *token {
+                Token::Literal(b) => {
+                    let symbol = usize::from(b);
+                    writer.write_bits(&mut out_buf, lengths[symbol].into(), codes[symbol].into());
+                },
+                Token::Match(length, offset) => {
+                    let symbol = match_symbol(length, offset);
+                    writer.write_bits(&mut out_buf, lengths[symbol].into(), codes[symbol].into());
+
+                    let extra_len = length - MATCH_MIN_LEN;
+                    if extra_len >= 15 {
+                        out_buf.push(cmp::min(extra_len - 15, 255) as u8);
+                        if (extra_len - 15) >= 255 {
+                            out_buf.push(extra_len as u8);
+                            out_buf.push((extra_len >> 8) as u8);
+                        }
+                    }
+
+                    let offset_bits = high_bit(offset);
+                    writer.write_bits(&mut out_buf, offset_bits, (offset - (1 << offset_bits)) as u32);
+                },
+            }
+        }
+
+        if is_last {
+            let symbol = HUFFMAN_EOF_SYMBOL;
+            writer.write_bits(&mut out_buf, lengths[symbol].into(), codes[symbol].into());
+        }
+
+        writer.flush(&mut out_buf);
+
+        if is_last {
+            break;
+        }
+        block_start = block_end;
+    }
+
+    Ok(out_buf)
+}
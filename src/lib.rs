@@ -1,3 +1,17 @@
 pub mod error;
 pub mod data;
 pub mod lznt1;
+pub mod huffman;
+pub mod hiberfil;
+pub mod memstore;
+pub mod prefetch;
+pub mod smb2;
+pub mod pattern_v1;
+pub mod drsr;
+pub mod oxcrpc;
+pub mod wsman;
+pub mod ovba;
+pub mod compressapi;
+pub mod rtl;
+pub mod carve;
+pub mod fingerprint;
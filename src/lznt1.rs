@@ -1,8 +1,16 @@
+use std::borrow::Cow;
 use std::mem;
+use std::cmp;
+use std::ops::Range;
 
 pub use crate::error::Error;
 
+use crate::data::TokenKind;
+pub use crate::data::{DecodeFailure, PartialOutput, Provenance, Source, StreamInfo};
+
 const LZNT1_COMPRESSED_FLAG: usize = 0x8000;
+const LZNT1_SIGNATURE:       usize = 0x3000;
+const LZNT1_CHUNK_SIZE:      usize = 0x1000;
 
 macro_rules! load16le{
     ($dst:expr,$src:expr,$idx:expr)=>{
@@ -13,18 +21,23 @@ macro_rules! load16le{
     }
 }
 
+// Upper bound of the decompressed size of in_len bytes: a chunk takes at
+// least 3 bytes (header and one byte of data) and holds at most 4096 bytes.
+pub(crate) fn max_decompressed_len(
+    in_len: usize
+) -> usize
+{
+    in_len.div_ceil(3).saturating_mul(LZNT1_CHUNK_SIZE)
+}
+
 pub fn decompress(
     in_buf: &[u8]
 ) -> Result<Vec<u8>, Error>
 {
-    
     let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len());
 
-    match decompress2_push(in_buf, &mut out_buf) {
-        Err(e) => println!("{:?}", e),
-        _ => ()
-    }
-    
+    decompress2_push(in_buf, &mut out_buf)?;
+
     Ok(out_buf)
 }
 
@@ -145,10 +158,30 @@ pub fn decompress2_push_old(
 }
 
 
+// Decodes every chunk up to a zero header or the end of the input at the
+// end of out_buf.
 pub fn decompress2_push(
     in_buf: &[u8],
     out_buf: &mut Vec<u8>
 ) -> Result<(), Error>
+{
+    for chunk in chunks(in_buf) {
+        let chunk = chunk?;
+
+        if chunk.compressed {
+            decompress_chunk(chunk.data, out_buf)?;
+        } else {
+            out_buf.extend_from_slice(chunk.data);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn decompress2_no_push(
+    in_buf: &[u8],
+    out_buf: &mut Vec<u8>
+) -> Result<(), Error>
 {
     let mut out_idx: usize = 0;
     let mut in_idx:  usize = 0;
@@ -162,6 +195,11 @@ pub fn decompress2_push(
 
     // We don't want to compute those values at each round.
     let in_buf_max_size = in_buf.len();
+    let out_buf_max_size = out_buf.len();
+
+    if out_buf_max_size == 0 || out_buf_max_size < in_buf_max_size {
+        return Err(Error::MemLimit);
+    }
 
     while in_idx < in_buf_max_size {
         let in_chunk_base = in_idx;
@@ -191,8 +229,12 @@ pub fn decompress2_push(
                     if in_idx >= in_buf_max_size || (in_idx - in_base_idx) >= chunk_len {
                         break;
                     }
+                    // out_buf.push(in_buf[in_idx]);
+                    if out_idx >= out_buf_max_size {
+                        return Err(Error::MemLimit);
+                    }
 
-                    out_buf.push(in_buf[in_idx]);
+                    out_buf[out_idx] = in_buf[in_idx];
                     out_idx += mem::size_of::<u8>();
                     in_idx += mem::size_of::<u8>();
                 } else {
@@ -222,18 +264,19 @@ pub fn decompress2_push(
                         return Err(Error::CorruptedData);
                     }
 
-                    /*
                     if (out_idx + length) >= out_buf_max_size {
                         length = out_buf_max_size - out_idx;
                     }
-                    */
                     
                     for _i in 0..length {
                         if offset > out_idx {
                             return Err(Error::CorruptedData);
                         }
-
-                        out_buf.push(out_buf[out_idx - offset]);
+                        // out_buf.push(out_buf[out_idx - offset]);
+                        if out_idx >= out_buf_max_size {
+                            return Err(Error::MemLimit);
+                        }
+                        out_buf[out_idx] = out_buf[out_idx - offset];
                         out_idx += mem::size_of::<u8>();
                     }
                 }
@@ -249,7 +292,11 @@ pub fn decompress2_push(
         } else {
             // Not compressed
             for _i in 0..chunk_len {
-                out_buf.push(in_buf[in_idx]);
+                // out_buf.push(in_buf[in_idx]);
+                if out_idx >= out_buf_max_size {
+                    return Err(Error::MemLimit);
+                }
+                out_buf[out_idx] = in_buf[in_idx];
                 out_idx += mem::size_of::<u8>();
                 in_idx += mem::size_of::<u8>();
             }
@@ -259,140 +306,610 @@ pub fn decompress2_push(
         _block_id += 1;
     }
 
+    unsafe {
+        out_buf.set_len(out_idx);
+    }
+
     Ok(())
 }
 
-pub fn decompress2_no_push(
-    in_buf: &[u8],
+// Copy token layout at position pos of the chunk: (max length, max offset, offset shift)
+pub(crate) fn copy_token_limits(
+    pos: usize
+) -> (usize, usize, usize)
+{
+    let mut pos = pos - 1;
+    let mut l_mask = 0xFFF;
+    let mut o_shift = 12;
+
+    while pos >= 0x10 {
+        l_mask >>= 1;
+        o_shift -= 1;
+        pos >>= 1;
+    }
+
+    (l_mask + 3, 1 << (16 - o_shift), o_shift)
+}
+
+fn compress_chunk(
+    chunk: &[u8],
     out_buf: &mut Vec<u8>
-) -> Result<(), Error>
+)
 {
-    let mut out_idx: usize = 0;
-    let mut in_idx:  usize = 0;
+    let header_idx = out_buf.len();
+    out_buf.push(0);
+    out_buf.push(0);
 
-    let mut header:     usize;
-    let mut length:     usize;
-    let mut chunk_len:  usize;
-    let mut offset:     usize;
+    let data_idx = out_buf.len();
 
-    let mut _block_id = 0;
+    let mut in_idx:   usize = 0;
+    let mut flag_idx: usize = 0;
+    let mut flag_bit: usize = 8;
 
-    // We don't want to compute those values at each round.
-    let in_buf_max_size = in_buf.len();
-    let out_buf_max_size = out_buf.len();
+    while in_idx < chunk.len() {
+        if flag_bit == 8 {
+            flag_idx = out_buf.len();
+            out_buf.push(0);
+            flag_bit = 0;
+        }
 
-    if out_buf_max_size == 0 || out_buf_max_size < in_buf_max_size {
-        return Err(Error::MemLimit);
+        let mut best_len: usize = 0;
+        let mut best_off: usize = 0;
+        let mut o_shift:  usize = 0;
+
+        if in_idx > 0 {
+            let (max_len, max_off, shift) = copy_token_limits(in_idx);
+            let max_len = cmp::min(max_len, chunk.len() - in_idx);
+            o_shift = shift;
+
+            // search for the longest match, the closest one wins ties
+            for offset in 1..=cmp::min(max_off, in_idx) {
+                let mut len = 0;
+                while len < max_len && chunk[in_idx + len] == chunk[in_idx - offset + len] {
+                    len += 1;
+                }
+
+                if len > best_len {
+                    best_len = len;
+                    best_off = offset;
+                    if len == max_len {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if best_len >= 3 {
+            let copy_token = ((best_off - 1) << o_shift) | (best_len - 3);
+            out_buf.push(copy_token as u8);
+            out_buf.push((copy_token >> 8) as u8);
+            out_buf[flag_idx] |= 1 << flag_bit;
+            in_idx += best_len;
+        } else {
+            out_buf.push(chunk[in_idx]);
+            in_idx += 1;
+        }
+
+        flag_bit += 1;
+
+        if (out_buf.len() - data_idx) >= chunk.len() {
+            break;
+        }
     }
 
-    while in_idx < in_buf_max_size {
-        let in_chunk_base = in_idx;
-        // compressed chunk header (2 bytes)
-        load16le!(header, in_buf, in_idx);
-        in_idx += mem::size_of::<u16>();
-        chunk_len = (header & 0xfff) + 1;
+    let chunk_len = out_buf.len() - data_idx;
 
-        if chunk_len > (in_buf_max_size - in_idx) {
-            return Err(Error::MemLimit);
+    if chunk_len >= chunk.len() {
+        // Not worth it, store the chunk as is.
+        out_buf.truncate(data_idx);
+        out_buf.extend_from_slice(chunk);
+
+        let header = LZNT1_SIGNATURE | (chunk.len() - 1);
+        out_buf[header_idx] = header as u8;
+        out_buf[header_idx + 1] = (header >> 8) as u8;
+    } else {
+        let header = LZNT1_COMPRESSED_FLAG | LZNT1_SIGNATURE | (chunk_len - 1);
+        out_buf[header_idx] = header as u8;
+        out_buf[header_idx + 1] = (header >> 8) as u8;
+    }
+}
+
+fn finish_chunk(
+    out_buf: &mut [u8],
+    header_idx: usize
+) -> Result<(), Error>
+{
+    let chunk_len = out_buf.len() - header_idx - mem::size_of::<u16>();
+
+    if chunk_len > LZNT1_CHUNK_SIZE {
+        return Err(Error::CorruptedData);
+    }
+
+    let header = LZNT1_COMPRESSED_FLAG | LZNT1_SIGNATURE | (chunk_len - 1);
+    out_buf[header_idx] = header as u8;
+    out_buf[header_idx + 1] = (header >> 8) as u8;
+
+    Ok(())
+}
+
+// Serializes the given tokens in compressed chunks, a new chunk starts every
+// 4096 bytes of output. Matches have to fit in the copy token layout of
+// their position and cannot cross a chunk boundary. The tokens of a chunk
+// have to take at most 4096 bytes once encoded, chunks are never stored.
+pub fn encode_tokens(
+    tokens: &[TokenKind]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    let mut header_idx: usize = 0;
+    let mut flag_idx:   usize = 0;
+    let mut flag_bit:   usize = 8;
+    let mut pos:        usize = LZNT1_CHUNK_SIZE;
+
+    for token in tokens {
+        if pos == LZNT1_CHUNK_SIZE {
+            if !out_buf.is_empty() {
+                finish_chunk(&mut out_buf, header_idx)?;
+            }
+
+            header_idx = out_buf.len();
+            out_buf.push(0);
+            out_buf.push(0);
+            flag_bit = 8;
+            pos = 0;
         }
 
-        if header & LZNT1_COMPRESSED_FLAG != 0 {
-            let in_base_idx = in_idx;
-            let out_base_idx = out_idx;
+        if flag_bit == 8 {
+            flag_idx = out_buf.len();
+            out_buf.push(0);
+            flag_bit = 0;
+        }
 
-            let mut flag_bit = 0;
-            let mut flags = in_buf[in_idx];
-            in_idx += mem::size_of::<u8>();
+        match *token {
+            TokenKind::Literal(b) => {
+                out_buf.push(b);
+                pos += 1;
+            },
+            TokenKind::Match { offset, length } => {
+                if pos == 0 {
+                    return Err(Error::CorruptedData);
+                }
 
-            while (in_idx - in_base_idx) < chunk_len {
-                if in_idx >= in_buf_max_size {
-                    break;
+                let (max_len, max_off, o_shift) = copy_token_limits(pos);
+                if offset == 0 || offset > cmp::min(max_off, pos)
+                    || length < 3 || length > cmp::min(max_len, LZNT1_CHUNK_SIZE - pos) {
+                    return Err(Error::CorruptedData);
                 }
 
-                if (flags & (1 << flag_bit)) == 0 {
-                    if in_idx >= in_buf_max_size || (in_idx - in_base_idx) >= chunk_len {
-                        break;
-                    }
-                    // out_buf.push(in_buf[in_idx]);
-                    if out_idx >= out_buf_max_size {
-                        return Err(Error::MemLimit);
-                    }
+                let copy_token = ((offset - 1) << o_shift) | (length - 3);
+                out_buf.push(copy_token as u8);
+                out_buf.push((copy_token >> 8) as u8);
+                out_buf[flag_idx] |= 1 << flag_bit;
+                pos += length;
+            },
+        }
 
-                    out_buf[out_idx] = in_buf[in_idx];
-                    out_idx += mem::size_of::<u8>();
-                    in_idx += mem::size_of::<u8>();
-                } else {
-                    let copy_token;
+        flag_bit += 1;
+    }
 
-                    if in_idx >= in_buf_max_size || (in_idx - in_base_idx) >= chunk_len {
-                        break;
-                    }
+    if !out_buf.is_empty() {
+        finish_chunk(&mut out_buf, header_idx)?;
+    }
 
-                    load16le!(copy_token, in_buf, in_idx);
-                    in_idx += mem::size_of::<u16>();
+    Ok(out_buf)
+}
 
-                    let mut pos = out_idx - out_base_idx - 1;
-                    let mut l_mask = 0xFFF;
-                    let mut o_shift = 12;
+pub fn compress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len() + 2 * (in_buf.len() / LZNT1_CHUNK_SIZE + 1));
 
-                    while pos >= 0x10 {
-                        l_mask >>= 1;
-                        o_shift -= 1;
-                        pos >>= 1;
-                    }
+    for chunk in in_buf.chunks(LZNT1_CHUNK_SIZE) {
+        compress_chunk(chunk, &mut out_buf);
+    }
 
-                    length = (copy_token & l_mask) + 3;
-                    offset = (copy_token >> o_shift) + 1;
+    Ok(out_buf)
+}
 
-                    if offset > out_idx {
-                        return Err(Error::CorruptedData);
-                    }
+// Decodes a single compressed chunk (header excluded) at the end of out_buf.
+pub(crate) fn decompress_chunk(
+    chunk: &[u8],
+    out_buf: &mut Vec<u8>
+) -> Result<(), Error>
+{
+    decompress_chunk_provenance(chunk, 0, out_buf, None)
+}
 
-                    if (out_idx + length) >= out_buf_max_size {
-                        length = out_buf_max_size - out_idx;
-                    }
-                    
-                    for _i in 0..length {
-                        if offset > out_idx {
-                            return Err(Error::CorruptedData);
-                        }
-                        // out_buf.push(out_buf[out_idx - offset]);
-                        if out_idx >= out_buf_max_size {
-                            return Err(Error::MemLimit);
-                        }
-                        out_buf[out_idx] = out_buf[out_idx - offset];
-                        out_idx += mem::size_of::<u8>();
-                    }
+// in_base_idx is the input offset of the chunk data, for the provenance.
+fn decompress_chunk_provenance(
+    chunk: &[u8],
+    in_base_idx: usize,
+    out_buf: &mut Vec<u8>,
+    mut provenance: Option<&mut Provenance>
+) -> Result<(), Error>
+{
+    walk_chunk(chunk, |in_idx, token| {
+        match token {
+            TokenKind::Literal(b) => {
+                if let Some(provenance) = provenance.as_deref_mut() {
+                    provenance.push_literals(out_buf.len(), in_base_idx + in_idx, 1);
                 }
 
-                flag_bit = (flag_bit + 1) % 8;
+                out_buf.push(b);
+            },
+            TokenKind::Match { offset, length } => {
+                if let Some(provenance) = provenance.as_deref_mut() {
+                    provenance.push_match(out_buf.len(), in_base_idx + in_idx, offset, length);
+                }
 
-                if flag_bit == 0 {
-                    if (in_idx - in_base_idx) >= chunk_len { break; }
-                    flags = in_buf[in_idx];
-                    in_idx += mem::size_of::<u8>();
+                for _i in 0..length {
+                    let b = out_buf[out_buf.len() - offset];
+                    out_buf.push(b);
                 }
+            },
+        }
+    })?;
+
+    Ok(())
+}
+
+// Walks the tokens of a compressed chunk (header excluded) and returns its
+// decoded size. visit gets the input offset of every token once it has been
+// checked against the output produced so far.
+fn walk_chunk(
+    chunk: &[u8],
+    mut visit: impl FnMut(usize, TokenKind)
+) -> Result<usize, Error>
+{
+    let mut in_idx: usize = 0;
+    let mut pos:    usize = 0;
+
+    while in_idx < chunk.len() {
+        let flags = chunk[in_idx];
+        in_idx += mem::size_of::<u8>();
+
+        for flag_bit in 0..8 {
+            if in_idx >= chunk.len() {
+                break;
             }
-        } else {
-            // Not compressed
-            for _i in 0..chunk_len {
-                // out_buf.push(in_buf[in_idx]);
-                if out_idx >= out_buf_max_size {
+
+            if (flags & (1 << flag_bit)) == 0 {
+                visit(in_idx, TokenKind::Literal(chunk[in_idx]));
+                in_idx += mem::size_of::<u8>();
+                pos += 1;
+            } else {
+                let copy_token: usize;
+
+                if (chunk.len() - in_idx) < mem::size_of::<u16>() {
                     return Err(Error::MemLimit);
                 }
-                out_buf[out_idx] = in_buf[in_idx];
-                out_idx += mem::size_of::<u8>();
-                in_idx += mem::size_of::<u8>();
+
+                load16le!(copy_token, chunk, in_idx);
+
+                if pos == 0 {
+                    return Err(Error::CorruptedData);
+                }
+
+                let (_, _, o_shift) = copy_token_limits(pos);
+                let length = (copy_token & ((1 << o_shift) - 1)) + 3;
+                let offset = (copy_token >> o_shift) + 1;
+
+                if offset > pos {
+                    return Err(Error::CorruptedData);
+                }
+
+                visit(in_idx, TokenKind::Match { offset, length });
+                in_idx += mem::size_of::<u16>();
+                pos += length;
+            }
+
+            if pos > LZNT1_CHUNK_SIZE {
+                return Err(Error::CorruptedData);
             }
         }
+    }
 
-        in_idx = in_chunk_base + 2 + chunk_len;
-        _block_id += 1;
+    Ok(pos)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    // Input offset of the chunk header
+    pub offset:     usize,
+    pub compressed: bool,
+    // Signature bits of the header, 0x3000 for a well formed chunk
+    pub signature:  u16,
+    // Stored size, header excluded
+    pub size:       usize,
+    pub data:       &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    // Stored chunks are returned as is.
+    pub fn decompress(&self) -> Result<Cow<'a, [u8]>, Error> {
+        if !self.compressed {
+            return Ok(Cow::Borrowed(self.data));
+        }
+
+        let mut out_buf: Vec<u8> = Vec::with_capacity(LZNT1_CHUNK_SIZE);
+        decompress_chunk(self.data, &mut out_buf)?;
+
+        Ok(Cow::Owned(out_buf))
     }
+}
 
-    unsafe {
-        out_buf.set_len(out_idx);
+// Iterates over the chunk headers, up to the end of the input or a zero
+// header. A truncated chunk is returned as an error and ends the iteration.
+pub struct Chunks<'a> {
+    in_buf: &'a [u8],
+    in_idx: usize,
+    done:   bool,
+}
+
+impl Chunks<'_> {
+    // Input bytes walked so far, a terminating zero header included.
+    pub fn position(&self) -> usize {
+        self.in_idx
     }
 
-    Ok(())
-}
\ No newline at end of file
+    fn fail(
+        &mut self,
+        error: Error
+    ) -> Option<Result<Chunk<'static>, Error>>
+    {
+        self.done = true;
+        Some(Err(error))
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Chunk<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header: usize;
+
+        if self.done || self.in_idx >= self.in_buf.len() {
+            return None;
+        }
+
+        let offset = self.in_idx;
+
+        if (self.in_buf.len() - self.in_idx) < mem::size_of::<u16>() {
+            return self.fail(Error::MemLimit);
+        }
+
+        load16le!(header, self.in_buf, self.in_idx);
+
+        if header == 0 {
+            self.in_idx += mem::size_of::<u16>();
+            self.done = true;
+            return None;
+        }
+
+        let chunk_len = (header & 0xfff) + 1;
+        if chunk_len > (self.in_buf.len() - self.in_idx - mem::size_of::<u16>()) {
+            return self.fail(Error::MemLimit);
+        }
+
+        self.in_idx += mem::size_of::<u16>();
+        let data = &self.in_buf[self.in_idx..self.in_idx + chunk_len];
+        self.in_idx += chunk_len;
+
+        Some(Ok(Chunk {
+            offset,
+            compressed: header & LZNT1_COMPRESSED_FLAG != 0,
+            signature: (header & 0x7000) as u16,
+            size: chunk_len,
+            data,
+        }))
+    }
+}
+
+pub fn chunks(
+    in_buf: &[u8]
+) -> Chunks<'_>
+{
+    Chunks { in_buf, in_idx: 0, done: false }
+}
+
+// Decoded size of a compressed chunk, without producing it.
+pub(crate) fn validate_chunk(
+    chunk: &[u8]
+) -> Result<usize, Error>
+{
+    walk_chunk(chunk, |_, _| {})
+}
+
+// Walks the whole stream (up to a zero header) and checks every copy token
+// without producing any output.
+pub fn validate(
+    in_buf: &[u8]
+) -> Result<StreamInfo, Error>
+{
+    let mut info = StreamInfo::default();
+    let mut chunks = chunks(in_buf);
+
+    for chunk in &mut chunks {
+        let chunk = chunk?;
+
+        if chunk.compressed {
+            info.uncompressed_size += validate_chunk(chunk.data)?;
+        } else {
+            info.uncompressed_size += chunk.size;
+        }
+    }
+
+    info.compressed_size = chunks.position();
+
+    Ok(info)
+}
+
+// Decodes up to a zero header or the end of the input and returns the
+// number of input bytes the stream took, the zero header included.
+pub fn decompress_consumed(
+    in_buf: &[u8]
+) -> Result<(Vec<u8>, usize), Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len());
+    let mut chunks = chunks(in_buf);
+
+    for chunk in &mut chunks {
+        let chunk = chunk?;
+
+        if chunk.compressed {
+            decompress_chunk(chunk.data, &mut out_buf)?;
+        } else {
+            out_buf.extend_from_slice(chunk.data);
+        }
+    }
+
+    Ok((out_buf, chunks.position()))
+}
+
+// Keeps the output produced up to the first error instead of dropping it,
+// the failing chunk included.
+pub fn decompress_lenient(
+    in_buf: &[u8]
+) -> PartialOutput
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len());
+    let mut chunks = chunks(in_buf);
+
+    loop {
+        let in_idx = chunks.position();
+        let result = match chunks.next() {
+            None => break,
+            Some(Err(e)) => Err(e),
+            Some(Ok(chunk)) if chunk.compressed => decompress_chunk(chunk.data, &mut out_buf),
+            Some(Ok(chunk)) => {
+                out_buf.extend_from_slice(chunk.data);
+                Ok(())
+            },
+        };
+
+        if let Err(error) = result {
+            let out_idx = out_buf.len();
+            return PartialOutput { out_buf, failure: Some(DecodeFailure { error, in_idx, out_idx }) };
+        }
+    }
+
+    PartialOutput { out_buf, failure: None }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    // Input offset of the chunk header
+    pub offset:    usize,
+    // Output replaced by the fill bytes
+    pub out_range: Range<usize>,
+    pub error:     Error,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovered {
+    pub out_buf: Vec<u8>,
+    pub damage:  Vec<Damage>,
+}
+
+// Chunks are self-contained: a chunk that fails to decode is replaced by
+// 4096 fill bytes and decoding resumes at the next header. A chunk running
+// past the end of the input is filled as well and ends the stream.
+pub fn decompress_recover(
+    in_buf: &[u8],
+    fill: u8
+) -> Recovered
+{
+    let mut recovered = Recovered::default();
+    let mut chunks = chunks(in_buf);
+
+    loop {
+        let offset = chunks.position();
+        let out_base_idx = recovered.out_buf.len();
+
+        let result = match chunks.next() {
+            None => break,
+            Some(Err(e)) => Err(e),
+            Some(Ok(chunk)) if chunk.compressed => decompress_chunk(chunk.data, &mut recovered.out_buf),
+            Some(Ok(chunk)) => {
+                recovered.out_buf.extend_from_slice(chunk.data);
+                Ok(())
+            },
+        };
+
+        if let Err(error) = result {
+            recovered.out_buf.truncate(out_base_idx);
+            recovered.out_buf.resize(out_base_idx + LZNT1_CHUNK_SIZE, fill);
+            recovered.damage.push(Damage {
+                offset,
+                out_range: out_base_idx..out_base_idx + LZNT1_CHUNK_SIZE,
+                error,
+            });
+        }
+    }
+
+    recovered
+}
+
+// Same as decompress_consumed, along with the origin of every output byte.
+pub fn decompress_with_provenance(
+    in_buf: &[u8]
+) -> Result<(Vec<u8>, Provenance), Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len());
+    let mut provenance = Provenance::default();
+
+    for chunk in chunks(in_buf) {
+        let chunk = chunk?;
+        let in_base_idx = chunk.offset + mem::size_of::<u16>();
+
+        if chunk.compressed {
+            decompress_chunk_provenance(chunk.data, in_base_idx, &mut out_buf, Some(&mut provenance))?;
+        } else {
+            provenance.push_literals(out_buf.len(), in_base_idx, chunk.size);
+            out_buf.extend_from_slice(chunk.data);
+        }
+    }
+
+    Ok((out_buf, provenance))
+}
+
+// Random access the way RtlDecompressFragment does it. Chunks are not
+// padded, as with decompress: the chunks before uncompressed_offset are
+// walked for their size only, without producing any output.
+pub fn decompress_range(
+    in_buf: &[u8],
+    uncompressed_offset: usize,
+    len: usize
+) -> Result<Vec<u8>, Error>
+{
+    // len is not trusted, the input bounds what the range can hold.
+    let mut out_buf: Vec<u8> = Vec::with_capacity(cmp::min(len, max_decompressed_len(in_buf.len())));
+    let mut chunk_base: usize = 0;
+
+    let range_end = uncompressed_offset.saturating_add(len);
+
+    for chunk in chunks(in_buf) {
+        if chunk_base >= range_end {
+            break;
+        }
+
+        let chunk = chunk?;
+        let chunk_len = if chunk.compressed { validate_chunk(chunk.data)? } else { chunk.size };
+
+        if (chunk_base + chunk_len) <= uncompressed_offset {
+            chunk_base += chunk_len;
+            continue;
+        }
+
+        let chunk_out = chunk.decompress()?;
+        let start = uncompressed_offset.saturating_sub(chunk_base);
+        let end = cmp::min(range_end - chunk_base, chunk_out.len());
+        out_buf.extend_from_slice(&chunk_out[start..end]);
+
+        chunk_base += chunk_out.len();
+    }
+
+    Ok(out_buf)
+}
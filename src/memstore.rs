@@ -0,0 +1,86 @@
+pub use crate::error::Error;
+
+use crate::data;
+
+// Windows 10+ memory compression store: pages are Plain LZ77 compressed
+// individually and packed in the store regions of the MemCompression process.
+pub const PAGE_SIZE: usize = 0x1000;
+
+// Location of a compressed page, relative to the region bytes handed to
+// decode_page()/decode_pages().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRecord {
+    pub offset:          usize,
+    pub compressed_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFailure {
+    // Index of the record in the records slice
+    pub index:  usize,
+    pub record: PageRecord,
+    pub error:  Error,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorePages {
+    // One entry per record, None when the page could not be decoded
+    pub pages:    Vec<Option<Vec<u8>>>,
+    pub failures: Vec<PageFailure>,
+}
+
+pub fn decode_page(
+    region: &[u8],
+    record: &PageRecord
+) -> Result<Vec<u8>, Error>
+{
+    if record.compressed_size == 0 || record.compressed_size > PAGE_SIZE {
+        return Err(Error::CorruptedData);
+    }
+
+    let end = match record.offset.checked_add(record.compressed_size) {
+        Some(end) if end <= region.len() => end,
+        _ => return Err(Error::MemLimit),
+    };
+
+    let in_buf = &region[record.offset..end];
+
+    // Pages that do not compress are stored as is.
+    if record.compressed_size == PAGE_SIZE {
+        return Ok(in_buf.to_vec());
+    }
+
+    let out_buf = data::decompress_bounded(in_buf, PAGE_SIZE)?;
+    if out_buf.len() != PAGE_SIZE {
+        return Err(Error::CorruptedData);
+    }
+
+    Ok(out_buf)
+}
+
+pub fn decode_pages(
+    region: &[u8],
+    records: &[PageRecord]
+) -> StorePages
+{
+    let mut store_pages = StorePages {
+        pages: Vec::with_capacity(records.len()),
+        failures: Vec::new(),
+    };
+
+    for (index, record) in records.iter().enumerate() {
+        match decode_page(region, record) {
+            Ok(page) => store_pages.pages.push(Some(page)),
+            Err(error) => {
+                store_pages.pages.push(None);
+                store_pages.failures.push(PageFailure {
+                    index,
+                    record: *record,
+                    error,
+                });
+            },
+        }
+    }
+
+    store_pages
+}
@@ -0,0 +1,168 @@
+use std::mem;
+use std::cmp;
+
+pub use crate::error::Error;
+
+use crate::lznt1;
+
+// [MS-OVBA] 2.4.1 CompressedContainer: a signature byte followed by chunks of
+// at most 4096 decompressed bytes. The chunk header holds the chunk size
+// minus 3 (header included), the 0b011 signature and the compressed flag,
+// which is the LZNT1 header layout. A raw chunk always holds 4096 bytes.
+const OVBA_SIGNATURE:        u8 = 0x01;
+const OVBA_COMPRESSED_FLAG:  usize = 0x8000;
+const OVBA_CHUNK_SIGNATURE:  usize = 0x3000;
+const OVBA_CHUNK_SIZE:       usize = 0x1000;
+
+macro_rules! load16le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = (u32::from($src[$idx + 1]) << 8
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+// A raw chunk is returned whole, as MS-OVBA 2.4.1.3.3 does: the container
+// does not record the original size, so the padding of a raw last chunk
+// is part of the output.
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len() * 2);
+    let mut in_idx: usize = 0;
+
+    let mut header:    usize;
+    let mut chunk_len: usize;
+
+    if in_buf.first() != Some(&OVBA_SIGNATURE) {
+        return Err(Error::InvalidSignature);
+    }
+    in_idx += mem::size_of::<u8>();
+
+    while in_idx < in_buf.len() {
+        if (in_buf.len() - in_idx) < mem::size_of::<u16>() {
+            return Err(Error::MemLimit);
+        }
+
+        load16le!(header, in_buf, in_idx);
+        in_idx += mem::size_of::<u16>();
+
+        if header & 0x7000 != OVBA_CHUNK_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+
+        // CompressedChunkSize - 3, minus the header itself
+        chunk_len = (header & 0xfff) + 1;
+
+        // The last chunk may be cut short by the end of the container.
+        let chunk_end = in_idx + chunk_len;
+        let chunk = &in_buf[in_idx..chunk_end.min(in_buf.len())];
+
+        // [MS-OVBA] 2.4.1.3.19.1: the copy token split is the LZNT1 one.
+        if header & OVBA_COMPRESSED_FLAG != 0 {
+            lznt1::decompress_chunk(chunk, &mut out_buf)?;
+        } else {
+            out_buf.extend_from_slice(chunk);
+        }
+
+        in_idx = chunk_end;
+    }
+
+    Ok(out_buf)
+}
+
+// [MS-OVBA] 2.4.1.3.7: the chunk stays compressed as long as it fits in
+// 4096 bytes, otherwise it is stored raw and padded to 4096 bytes.
+fn compress_chunk(
+    chunk: &[u8],
+    out_buf: &mut Vec<u8>
+)
+{
+    let header_idx = out_buf.len();
+    out_buf.push(0);
+    out_buf.push(0);
+
+    let data_idx = out_buf.len();
+
+    let mut in_idx:   usize = 0;
+    let mut flag_idx: usize = 0;
+    let mut flag_bit: usize = 8;
+
+    while in_idx < chunk.len() && (out_buf.len() - data_idx) <= OVBA_CHUNK_SIZE {
+        if flag_bit == 8 {
+            flag_idx = out_buf.len();
+            out_buf.push(0);
+            flag_bit = 0;
+        }
+
+        let mut best_len: usize = 0;
+        let mut best_off: usize = 0;
+        let mut o_shift:  usize = 0;
+
+        if in_idx > 0 {
+            let (max_len, max_off, shift) = lznt1::copy_token_limits(in_idx);
+            let max_len = cmp::min(max_len, chunk.len() - in_idx);
+            o_shift = shift;
+
+            // longest match, the closest one wins ties
+            for offset in 1..=cmp::min(max_off, in_idx) {
+                let mut len = 0;
+                while len < max_len && chunk[in_idx + len] == chunk[in_idx - offset + len] {
+                    len += 1;
+                }
+
+                if len > best_len {
+                    best_len = len;
+                    best_off = offset;
+                    if len == max_len {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if best_len >= 3 {
+            let copy_token = ((best_off - 1) << o_shift) | (best_len - 3);
+            out_buf.push(copy_token as u8);
+            out_buf.push((copy_token >> 8) as u8);
+            out_buf[flag_idx] |= 1 << flag_bit;
+            in_idx += best_len;
+        } else {
+            out_buf.push(chunk[in_idx]);
+            in_idx += 1;
+        }
+
+        flag_bit += 1;
+    }
+
+    let chunk_len = out_buf.len() - data_idx;
+
+    let header = if chunk_len > OVBA_CHUNK_SIZE {
+        out_buf.truncate(data_idx);
+        out_buf.extend_from_slice(chunk);
+        out_buf.resize(data_idx + OVBA_CHUNK_SIZE, 0);
+
+        OVBA_CHUNK_SIGNATURE | (OVBA_CHUNK_SIZE - 1)
+    } else {
+        OVBA_COMPRESSED_FLAG | OVBA_CHUNK_SIGNATURE | (chunk_len - 1)
+    };
+
+    out_buf[header_idx] = header as u8;
+    out_buf[header_idx + 1] = (header >> 8) as u8;
+}
+
+pub fn compress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len() + 2 * (in_buf.len() / OVBA_CHUNK_SIZE + 1) + 1);
+    out_buf.push(OVBA_SIGNATURE);
+
+    for chunk in in_buf.chunks(OVBA_CHUNK_SIZE) {
+        compress_chunk(chunk, &mut out_buf);
+    }
+
+    Ok(out_buf)
+}
@@ -0,0 +1,206 @@
+pub use crate::error::Error;
+
+use crate::data;
+
+// [MS-OXCRPC] 2.2.2.1 RPC_HEADER_EXT: Version, Flags, Size (of the payload
+// that follows) and SizeActual (once uncompressed), all 16 bits.
+pub const RPC_HEADER_EXT_SIZE: usize = 8;
+pub const RPC_HEADER_EXT_VERSION: u16 = 0x0000;
+
+// Flag values from MS-OXCRPC 2.2.2.1
+
+// Payload compressed with Plain LZ77
+pub const FLAG_COMPRESSED: u16 = 0x0001;
+// Payload bytes XORed with 0xA5 (after compression)
+pub const FLAG_XOR_MAGIC:  u16 = 0x0002;
+// No extended buffer follows
+pub const FLAG_LAST:       u16 = 0x0004;
+
+const XOR_MAGIC: u8 = 0xA5;
+
+pub const MAX_PAYLOAD_SIZE: usize = 0xFFFF;
+
+macro_rules! load16le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = (u32::from($src[$idx + 1]) << 8
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpcHeaderExt {
+    pub version:     u16,
+    pub flags:       u16,
+    pub size:        u16,
+    pub size_actual: u16,
+}
+
+impl RpcHeaderExt {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<RpcHeaderExt, Error>
+    {
+        let version:     usize;
+        let flags:       usize;
+        let size:        usize;
+        let size_actual: usize;
+
+        if in_buf.len() < RPC_HEADER_EXT_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load16le!(version, in_buf, 0);
+        load16le!(flags, in_buf, 2);
+        load16le!(size, in_buf, 4);
+        load16le!(size_actual, in_buf, 6);
+
+        if version as u16 != RPC_HEADER_EXT_VERSION {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(RpcHeaderExt {
+            version: version as u16,
+            flags: flags as u16,
+            size: size as u16,
+            size_actual: size_actual as u16,
+        })
+    }
+
+    pub fn write(
+        &self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        out_buf.extend_from_slice(&self.version.to_le_bytes());
+        out_buf.extend_from_slice(&self.flags.to_le_bytes());
+        out_buf.extend_from_slice(&self.size.to_le_bytes());
+        out_buf.extend_from_slice(&self.size_actual.to_le_bytes());
+    }
+}
+
+fn xor_magic(
+    buf: &mut [u8]
+)
+{
+    for b in buf.iter_mut() {
+        *b ^= XOR_MAGIC;
+    }
+}
+
+// Returns the payload of each extended buffer of the chain, up to and
+// including the one flagged Last.
+pub fn decompress_buffers(
+    in_buf: &[u8]
+) -> Result<Vec<Vec<u8>>, Error>
+{
+    let mut in_idx: usize = 0;
+    let mut buffers: Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        if in_idx >= in_buf.len() {
+            // The chain ended without a Last buffer.
+            return Err(Error::CorruptedData);
+        }
+
+        let header = RpcHeaderExt::parse(&in_buf[in_idx..])?;
+        in_idx += RPC_HEADER_EXT_SIZE;
+
+        let size = usize::from(header.size);
+        if size > (in_buf.len() - in_idx) {
+            return Err(Error::MemLimit);
+        }
+
+        let mut payload = in_buf[in_idx..in_idx + size].to_vec();
+        in_idx += size;
+
+        if header.flags & FLAG_XOR_MAGIC != 0 {
+            xor_magic(&mut payload);
+        }
+
+        if header.flags & FLAG_COMPRESSED != 0 {
+            payload = data::decompress_exact(&payload, usize::from(header.size_actual))?;
+        } else if header.size != header.size_actual {
+            return Err(Error::CorruptedData);
+        }
+
+        buffers.push(payload);
+
+        if header.flags & FLAG_LAST != 0 {
+            break;
+        }
+    }
+
+    Ok(buffers)
+}
+
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    Ok(decompress_buffers(in_buf)?.concat())
+}
+
+// Wraps each payload in an extended buffer. flags may request
+// FLAG_COMPRESSED and/or FLAG_XOR_MAGIC, compression is dropped for the
+// payloads it does not make smaller. Last is set on the final buffer.
+pub fn compress_buffers(
+    payloads: &[&[u8]],
+    flags: u16
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    for (i, payload) in payloads.iter().enumerate() {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        let mut buffer_flags = flags & (FLAG_COMPRESSED | FLAG_XOR_MAGIC);
+        let mut body = payload.to_vec();
+
+        if buffer_flags & FLAG_COMPRESSED != 0 {
+            let compressed = data::compress(payload)?;
+            if compressed.len() < payload.len() {
+                body = compressed;
+            } else {
+                buffer_flags &= !FLAG_COMPRESSED;
+            }
+        }
+
+        if buffer_flags & FLAG_XOR_MAGIC != 0 {
+            xor_magic(&mut body);
+        }
+
+        if (i + 1) == payloads.len() {
+            buffer_flags |= FLAG_LAST;
+        }
+
+        let header = RpcHeaderExt {
+            version: RPC_HEADER_EXT_VERSION,
+            flags: buffer_flags,
+            size: body.len() as u16,
+            size_actual: payload.len() as u16,
+        };
+
+        header.write(&mut out_buf);
+        out_buf.extend_from_slice(&body);
+    }
+
+    Ok(out_buf)
+}
+
+// Splits in_buf in as many extended buffers as needed.
+pub fn compress(
+    in_buf: &[u8],
+    flags: u16
+) -> Result<Vec<u8>, Error>
+{
+    let mut payloads: Vec<&[u8]> = in_buf.chunks(MAX_PAYLOAD_SIZE).collect();
+    if payloads.is_empty() {
+        payloads.push(in_buf);
+    }
+
+    compress_buffers(&payloads, flags)
+}
@@ -0,0 +1,145 @@
+pub use crate::error::Error;
+
+// [MS-SMB2] 2.2.42.2.3 Pattern_V1: Pattern (1 byte), Reserved1 (1 byte),
+// Reserved2 (2 bytes), Repetitions (4 bytes)
+pub const PAYLOAD_SIZE: usize = 8;
+
+// Shorter runs are left to the regular compression of the payload, a
+// Pattern_V1 payload and its header cost 16 bytes.
+pub const MIN_RUN_LENGTH: usize = 32;
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    pub pattern:     u8,
+    pub repetitions: u32,
+}
+
+impl Pattern {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<Pattern, Error>
+    {
+        let repetitions: usize;
+
+        if in_buf.len() != PAYLOAD_SIZE {
+            return Err(Error::CorruptedData);
+        }
+
+        load32le!(repetitions, in_buf, 4);
+
+        Ok(Pattern {
+            pattern: in_buf[0],
+            repetitions: repetitions as u32,
+        })
+    }
+
+    pub fn write(
+        &self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        out_buf.push(self.pattern);
+        out_buf.extend_from_slice(&[0, 0, 0]);
+        out_buf.extend_from_slice(&self.repetitions.to_le_bytes());
+    }
+}
+
+// in_buf has to be a run of a single byte value.
+pub fn compress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let pattern = match in_buf.first() {
+        Some(pattern) => *pattern,
+        None => return Err(Error::CorruptedData),
+    };
+
+    if in_buf.len() > u32::MAX as usize {
+        return Err(Error::MemLimit);
+    }
+
+    if in_buf.iter().any(|b| *b != pattern) {
+        return Err(Error::CorruptedData);
+    }
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(PAYLOAD_SIZE);
+    Pattern { pattern, repetitions: in_buf.len() as u32 }.write(&mut out_buf);
+
+    Ok(out_buf)
+}
+
+// Fails with MemLimit when the run is longer than out_max.
+pub fn decompress(
+    in_buf: &[u8],
+    out_max: usize
+) -> Result<Vec<u8>, Error>
+{
+    let pattern = Pattern::parse(in_buf)?;
+
+    if pattern.repetitions as usize > out_max {
+        return Err(Error::MemLimit);
+    }
+
+    Ok(vec![pattern.pattern; pattern.repetitions as usize])
+}
+
+// Lengths of the leading and trailing runs of in_buf worth sending as
+// Pattern_V1 payloads, 0 when there is none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Runs {
+    pub leading:  usize,
+    pub trailing: usize,
+}
+
+impl Runs {
+    // What is left between the two runs.
+    pub fn middle<'a>(
+        &self,
+        in_buf: &'a [u8]
+    ) -> &'a [u8]
+    {
+        &in_buf[self.leading..in_buf.len() - self.trailing]
+    }
+}
+
+pub fn scan(
+    in_buf: &[u8],
+    min_run: usize
+) -> Runs
+{
+    let mut runs = Runs::default();
+
+    let first = match in_buf.first() {
+        Some(first) => *first,
+        None => return runs,
+    };
+
+    let leading = in_buf.iter().take_while(|b| **b == first).count();
+    if leading >= min_run {
+        runs.leading = leading;
+    }
+
+    // A single run covers the whole buffer, nothing trails it.
+    if leading == in_buf.len() {
+        return runs;
+    }
+
+    let last = in_buf[in_buf.len() - 1];
+    let trailing = in_buf.iter().rev().take_while(|b| **b == last).count();
+    if trailing >= min_run {
+        runs.trailing = trailing;
+    }
+
+    runs
+}
@@ -0,0 +1,151 @@
+use std::mem;
+
+pub use crate::error::Error;
+
+use crate::data;
+use crate::huffman;
+
+// Windows 10+ prefetch files: "MAM" + format byte, the uncompressed size and,
+// when bit 7 of the format byte is set, a CRC32 of the whole file computed
+// with the checksum field zeroed.
+const MAM_SIGNATURE:      &[u8] = b"MAM";
+const MAM_CHECKSUM_FLAG:  u8 = 0x80;
+const MAM_FORMAT_MASK:    u8 = 0x0f;
+const MAM_HEADER_SIZE:    usize = 8;
+const SCCA_SIGNATURE:     &[u8] = b"SCCA";
+const SCCA_SIGNATURE_OFF: usize = 4;
+
+// COMPRESSION_FORMAT_* values found in the low nibble of the format byte
+pub const FORMAT_XPRESS:      u8 = 3;
+pub const FORMAT_XPRESS_HUFF: u8 = 4;
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MamHeader {
+    pub format:            u8,
+    pub uncompressed_size: u32,
+    pub checksum:          Option<u32>,
+}
+
+impl MamHeader {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<MamHeader, Error>
+    {
+        let uncompressed_size: usize;
+
+        if in_buf.len() < MAM_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        if &in_buf[..MAM_SIGNATURE.len()] != MAM_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+
+        // Bits 4..6 are not defined by any known writer.
+        if in_buf[3] & !(MAM_CHECKSUM_FLAG | MAM_FORMAT_MASK) != 0 {
+            return Err(Error::CorruptedData);
+        }
+
+        load32le!(uncompressed_size, in_buf, 4);
+
+        let mut checksum = None;
+        if in_buf[3] & MAM_CHECKSUM_FLAG != 0 {
+            let crc: usize;
+
+            if in_buf.len() < MAM_HEADER_SIZE + mem::size_of::<u32>() {
+                return Err(Error::MemLimit);
+            }
+
+            load32le!(crc, in_buf, MAM_HEADER_SIZE);
+            checksum = Some(crc as u32);
+        }
+
+        Ok(MamHeader {
+            format: in_buf[3] & MAM_FORMAT_MASK,
+            uncompressed_size: uncompressed_size as u32,
+            checksum,
+        })
+    }
+
+    // The compressed data follows the header.
+    pub fn header_size(&self) -> usize {
+        match self.checksum {
+            Some(_) => MAM_HEADER_SIZE + mem::size_of::<u32>(),
+            None => MAM_HEADER_SIZE,
+        }
+    }
+}
+
+fn crc32(
+    mut crc: u32,
+    in_buf: &[u8]
+) -> u32
+{
+    crc = !crc;
+    for b in in_buf {
+        crc ^= u32::from(*b);
+        for _i in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+pub fn is_compressed(
+    in_buf: &[u8]
+) -> bool
+{
+    in_buf.starts_with(MAM_SIGNATURE)
+}
+
+// Returns the uncompressed prefetch file (SCCA format).
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let header = MamHeader::parse(in_buf)?;
+    let compressed = &in_buf[header.header_size()..];
+
+    if let Some(expected) = header.checksum {
+        let mut crc = crc32(0, &in_buf[..MAM_HEADER_SIZE]);
+        crc = crc32(crc, &[0u8; 4]);
+        crc = crc32(crc, compressed);
+
+        if crc != expected {
+            return Err(Error::ChecksumMismatch);
+        }
+    }
+
+    let out_len = header.uncompressed_size as usize;
+    let out_buf = match header.format {
+        FORMAT_XPRESS => data::decompress_exact(compressed, out_len)?,
+        FORMAT_XPRESS_HUFF => huffman::decompress(compressed, out_len)?,
+        _ => return Err(Error::UnsupportedAlgorithm),
+    };
+
+    if out_buf.len() != out_len {
+        return Err(Error::CorruptedData);
+    }
+
+    if out_buf.len() < SCCA_SIGNATURE_OFF + SCCA_SIGNATURE.len()
+        || &out_buf[SCCA_SIGNATURE_OFF..SCCA_SIGNATURE_OFF + SCCA_SIGNATURE.len()] != SCCA_SIGNATURE {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(out_buf)
+}
@@ -0,0 +1,166 @@
+pub use crate::error::Error;
+
+use crate::data;
+use crate::huffman;
+use crate::lznt1;
+
+// CompressionFormatAndEngine of RtlCompressBuffer/RtlDecompressBuffer: the
+// format in the low byte, the engine in the high byte.
+pub const COMPRESSION_FORMAT_NONE:        u16 = 0x0000;
+pub const COMPRESSION_FORMAT_DEFAULT:     u16 = 0x0001;
+pub const COMPRESSION_FORMAT_LZNT1:       u16 = 0x0002;
+pub const COMPRESSION_FORMAT_XPRESS:      u16 = 0x0003;
+pub const COMPRESSION_FORMAT_XPRESS_HUFF: u16 = 0x0004;
+
+pub const COMPRESSION_ENGINE_STANDARD:    u16 = 0x0000;
+pub const COMPRESSION_ENGINE_MAXIMUM:     u16 = 0x0100;
+pub const COMPRESSION_ENGINE_HIBER:       u16 = 0x0200;
+
+const COMPRESSION_FORMAT_MASK: u16 = 0x00FF;
+const COMPRESSION_ENGINE_MASK: u16 = 0xFF00;
+
+pub const STATUS_SUCCESS:                 u32 = 0x0000_0000;
+pub const STATUS_BUFFER_TOO_SMALL:        u32 = 0xC000_0023;
+pub const STATUS_BAD_COMPRESSION_BUFFER:  u32 = 0xC000_0242;
+pub const STATUS_UNSUPPORTED_COMPRESSION: u32 = 0xC000_025F;
+pub const STATUS_INVALID_PARAMETER:       u32 = 0xC000_000D;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Lznt1,
+    Xpress,
+    XpressHuff,
+}
+
+fn parse_format_engine(
+    format_engine: u16
+) -> Result<Format, Error>
+{
+    match format_engine & COMPRESSION_ENGINE_MASK {
+        COMPRESSION_ENGINE_STANDARD | COMPRESSION_ENGINE_MAXIMUM | COMPRESSION_ENGINE_HIBER => (),
+        _ => return Err(Error::UnsupportedAlgorithm),
+    }
+
+    match format_engine & COMPRESSION_FORMAT_MASK {
+        // Windows takes them as a caller error, not an unknown format
+        COMPRESSION_FORMAT_NONE | COMPRESSION_FORMAT_DEFAULT => Err(Error::InvalidParameter),
+        COMPRESSION_FORMAT_LZNT1 => Ok(Format::Lznt1),
+        COMPRESSION_FORMAT_XPRESS => Ok(Format::Xpress),
+        COMPRESSION_FORMAT_XPRESS_HUFF => Ok(Format::XpressHuff),
+        _ => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
+// NTSTATUS the Rtl routines return for the same failure. The routines of
+// this module only return MemLimit when out_buf is too small, an input cut
+// short is reported as CorruptedData.
+pub fn ntstatus(
+    error: Error
+) -> u32
+{
+    match error {
+        Error::MemLimit => STATUS_BUFFER_TOO_SMALL,
+        Error::UnsupportedAlgorithm => STATUS_UNSUPPORTED_COMPRESSION,
+        Error::InvalidParameter => STATUS_INVALID_PARAMETER,
+        Error::CorruptedData
+        | Error::InvalidSignature
+        | Error::ChecksumMismatch
+        | Error::Other => STATUS_BAD_COMPRESSION_BUFFER,
+    }
+}
+
+// Stub of RtlGetCompressionWorkSpaceSize: checks format_engine and always
+// returns (0, 0) as (CompressBufferWorkSpaceSize,
+// CompressFragmentWorkSpaceSize), not the sizes Windows reports. The
+// routines of this crate allocate what they need and take no work space.
+pub fn get_compression_work_space_size(
+    format_engine: u16
+) -> Result<(u32, u32), Error>
+{
+    parse_format_engine(format_engine)?;
+
+    Ok((0, 0))
+}
+
+// RtlCompressBuffer: returns FinalCompressedSize.
+pub fn compress_buffer(
+    format_engine: u16,
+    in_buf: &[u8],
+    out_buf: &mut [u8]
+) -> Result<usize, Error>
+{
+    let compressed = match parse_format_engine(format_engine)? {
+        Format::Lznt1 => lznt1::compress(in_buf)?,
+        Format::Xpress => data::compress(in_buf)?,
+        Format::XpressHuff => huffman::compress(in_buf)?,
+    };
+
+    if compressed.len() > out_buf.len() {
+        return Err(Error::MemLimit);
+    }
+
+    out_buf[..compressed.len()].copy_from_slice(&compressed);
+
+    Ok(compressed.len())
+}
+
+// The decoders return MemLimit for a truncated input as well.
+fn truncated_input(
+    error: Error
+) -> Error
+{
+    match error {
+        Error::MemLimit => Error::CorruptedData,
+        error => error,
+    }
+}
+
+// Decodes chunk by chunk and stops at the first one that does not fit in out_max.
+fn decompress_lznt1(
+    in_buf: &[u8],
+    out_max: usize
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    for chunk in lznt1::chunks(in_buf) {
+        let chunk_out = chunk.and_then(|chunk| chunk.decompress()).map_err(truncated_input)?;
+
+        if chunk_out.len() > (out_max - out_buf.len()) {
+            return Err(Error::MemLimit);
+        }
+
+        out_buf.extend_from_slice(&chunk_out);
+    }
+
+    Ok(out_buf)
+}
+
+// RtlDecompressBuffer: returns FinalUncompressedSize. As with Windows,
+// XPRESS_HUFF needs out_buf to be the size of the uncompressed data.
+pub fn decompress_buffer(
+    format_engine: u16,
+    in_buf: &[u8],
+    out_buf: &mut [u8]
+) -> Result<usize, Error>
+{
+    let uncompressed = match parse_format_engine(format_engine)? {
+        Format::Lznt1 => decompress_lznt1(in_buf, out_buf.len())?,
+        // One byte past out_buf tells a short out_buf apart, whether the cut
+        // falls between two tokens or inside a match.
+        Format::Xpress => match data::decompress_bounded(in_buf, out_buf.len().saturating_add(1)) {
+            // Either a match running past out_buf or the end of the input
+            Err(Error::MemLimit) if data::validate(in_buf).is_err() => return Err(Error::CorruptedData),
+            result => result?,
+        },
+        Format::XpressHuff => huffman::decompress(in_buf, out_buf.len())?,
+    };
+
+    if uncompressed.len() > out_buf.len() {
+        return Err(Error::MemLimit);
+    }
+
+    out_buf[..uncompressed.len()].copy_from_slice(&uncompressed);
+
+    Ok(uncompressed.len())
+}
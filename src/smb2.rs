@@ -0,0 +1,426 @@
+use std::cmp;
+
+pub use crate::error::Error;
+
+use crate::data;
+use crate::huffman;
+use crate::lznt1;
+use crate::pattern_v1;
+
+// [MS-SMB2] 2.2.42 SMB2 COMPRESSION_TRANSFORM_HEADER
+pub const PROTOCOL_ID:                u32 = 0x424D_53FC;
+pub const TRANSFORM_HEADER_SIZE:      usize = 16;
+// Chained form: ProtocolId and OriginalCompressedSegmentSize, then a
+// sequence of COMPRESSION_PAYLOAD_HEADER and their payload.
+pub const CHAINED_HEADER_SIZE:        usize = 8;
+pub const PAYLOAD_HEADER_SIZE:        usize = 8;
+
+pub const COMPRESSION_FLAG_NONE:      u16 = 0x0000;
+pub const COMPRESSION_FLAG_CHAINED:   u16 = 0x0001;
+
+macro_rules! load16le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = (u32::from($src[$idx + 1]) << 8
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+macro_rules! load32le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = ((u32::from($src[$idx + 3]) << 24)
+            | (u32::from($src[$idx + 2]) << 16)
+            | (u32::from($src[$idx + 1]) << 8)
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    Lznt1,
+    Lz77,
+    Lz77Huffman,
+    PatternV1,
+    Lz4,
+}
+
+impl CompressionAlgorithm {
+    pub fn from_u16(
+        value: u16
+    ) -> Result<CompressionAlgorithm, Error>
+    {
+        match value {
+            0x0000 => Ok(CompressionAlgorithm::None),
+            0x0001 => Ok(CompressionAlgorithm::Lznt1),
+            0x0002 => Ok(CompressionAlgorithm::Lz77),
+            0x0003 => Ok(CompressionAlgorithm::Lz77Huffman),
+            0x0004 => Ok(CompressionAlgorithm::PatternV1),
+            0x0005 => Ok(CompressionAlgorithm::Lz4),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            CompressionAlgorithm::None => 0x0000,
+            CompressionAlgorithm::Lznt1 => 0x0001,
+            CompressionAlgorithm::Lz77 => 0x0002,
+            CompressionAlgorithm::Lz77Huffman => 0x0003,
+            CompressionAlgorithm::PatternV1 => 0x0004,
+            CompressionAlgorithm::Lz4 => 0x0005,
+        }
+    }
+}
+
+// Unchained form: the first offset bytes of the message follow the header
+// uncompressed, the rest is compressed and decompresses to
+// original_compressed_segment_size bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformHeader {
+    pub original_compressed_segment_size: u32,
+    pub compression_algorithm:            CompressionAlgorithm,
+    pub flags:                            u16,
+    pub offset:                           u32,
+}
+
+impl TransformHeader {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<TransformHeader, Error>
+    {
+        let protocol_id:   usize;
+        let original_size: usize;
+        let algorithm:     usize;
+        let flags:         usize;
+        let offset:        usize;
+
+        if in_buf.len() < TRANSFORM_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load32le!(protocol_id, in_buf, 0);
+        if protocol_id as u32 != PROTOCOL_ID {
+            return Err(Error::InvalidSignature);
+        }
+
+        load32le!(original_size, in_buf, 4);
+        load16le!(algorithm, in_buf, 8);
+        load16le!(flags, in_buf, 10);
+        load32le!(offset, in_buf, 12);
+
+        Ok(TransformHeader {
+            original_compressed_segment_size: original_size as u32,
+            compression_algorithm: CompressionAlgorithm::from_u16(algorithm as u16)?,
+            flags: flags as u16,
+            offset: offset as u32,
+        })
+    }
+
+    pub fn write(
+        &self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        out_buf.extend_from_slice(&PROTOCOL_ID.to_le_bytes());
+        out_buf.extend_from_slice(&self.original_compressed_segment_size.to_le_bytes());
+        out_buf.extend_from_slice(&self.compression_algorithm.to_u16().to_le_bytes());
+        out_buf.extend_from_slice(&self.flags.to_le_bytes());
+        out_buf.extend_from_slice(&self.offset.to_le_bytes());
+    }
+}
+
+// [MS-SMB2] 2.2.42.2.1 COMPRESSION_PAYLOAD_HEADER. For LZNT1, LZ77,
+// LZ77+Huffman and LZ4, length includes the 4 bytes OriginalPayloadSize
+// preceding the compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadHeader {
+    pub compression_algorithm: CompressionAlgorithm,
+    pub flags:                 u16,
+    pub length:                u32,
+}
+
+impl PayloadHeader {
+    pub fn parse(
+        in_buf: &[u8]
+    ) -> Result<PayloadHeader, Error>
+    {
+        let algorithm: usize;
+        let flags:     usize;
+        let length:    usize;
+
+        if in_buf.len() < PAYLOAD_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load16le!(algorithm, in_buf, 0);
+        load16le!(flags, in_buf, 2);
+        load32le!(length, in_buf, 4);
+
+        Ok(PayloadHeader {
+            compression_algorithm: CompressionAlgorithm::from_u16(algorithm as u16)?,
+            flags: flags as u16,
+            length: length as u32,
+        })
+    }
+
+    pub fn write(
+        &self,
+        out_buf: &mut Vec<u8>
+    )
+    {
+        out_buf.extend_from_slice(&self.compression_algorithm.to_u16().to_le_bytes());
+        out_buf.extend_from_slice(&self.flags.to_le_bytes());
+        out_buf.extend_from_slice(&self.length.to_le_bytes());
+    }
+}
+
+// One step of a chained message plan: data is sent with the given algorithm.
+// A PatternV1 step must be a run of a single byte value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainedPayload<'a> {
+    pub compression_algorithm: CompressionAlgorithm,
+    pub data:                  &'a [u8],
+}
+
+// Decompresses in_buf into exactly out_len bytes.
+pub fn decompress_payload(
+    algorithm: CompressionAlgorithm,
+    in_buf: &[u8],
+    out_len: usize
+) -> Result<Vec<u8>, Error>
+{
+    let out_buf = match algorithm {
+        CompressionAlgorithm::None => in_buf.to_vec(),
+        CompressionAlgorithm::Lznt1 => {
+            let capacity = cmp::min(out_len, lznt1::max_decompressed_len(in_buf.len()));
+            let mut out_buf: Vec<u8> = Vec::with_capacity(capacity);
+            lznt1::decompress2_push(in_buf, &mut out_buf)?;
+            out_buf
+        },
+        CompressionAlgorithm::Lz77 => data::decompress_exact(in_buf, out_len)?,
+        CompressionAlgorithm::Lz77Huffman => huffman::decompress(in_buf, out_len)?,
+        CompressionAlgorithm::PatternV1 | CompressionAlgorithm::Lz4 => {
+            return Err(Error::UnsupportedAlgorithm);
+        },
+    };
+
+    if out_buf.len() != out_len {
+        return Err(Error::CorruptedData);
+    }
+
+    Ok(out_buf)
+}
+
+pub fn compress_payload(
+    algorithm: CompressionAlgorithm,
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    match algorithm {
+        CompressionAlgorithm::None => Ok(in_buf.to_vec()),
+        CompressionAlgorithm::Lznt1 => lznt1::compress(in_buf),
+        CompressionAlgorithm::Lz77 => data::compress(in_buf),
+        CompressionAlgorithm::Lz77Huffman => huffman::compress(in_buf),
+        CompressionAlgorithm::PatternV1 | CompressionAlgorithm::Lz4 => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
+// Returns the original SMB2 message.
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let header = TransformHeader::parse(in_buf)?;
+
+    if header.flags & COMPRESSION_FLAG_CHAINED != 0 {
+        return decompress_chained(in_buf);
+    }
+
+    let payload = &in_buf[TRANSFORM_HEADER_SIZE..];
+    let offset = header.offset as usize;
+    if offset > payload.len() {
+        return Err(Error::MemLimit);
+    }
+
+    let out_len = header.original_compressed_segment_size as usize;
+    let segment = decompress_payload(header.compression_algorithm, &payload[offset..], out_len)?;
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(offset + segment.len());
+    out_buf.extend_from_slice(&payload[..offset]);
+    out_buf.extend_from_slice(&segment);
+
+    Ok(out_buf)
+}
+
+// Builds an unchained compressed message, the first offset bytes of the
+// message (e.g. the SMB2 header of a READ response) are left uncompressed.
+pub fn compress(
+    message: &[u8],
+    algorithm: CompressionAlgorithm,
+    offset: usize
+) -> Result<Vec<u8>, Error>
+{
+    if offset > message.len() {
+        return Err(Error::MemLimit);
+    }
+
+    let segment = &message[offset..];
+    let compressed = compress_payload(algorithm, segment)?;
+
+    let header = TransformHeader {
+        original_compressed_segment_size: segment.len() as u32,
+        compression_algorithm: algorithm,
+        flags: COMPRESSION_FLAG_NONE,
+        offset: offset as u32,
+    };
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(TRANSFORM_HEADER_SIZE + offset + compressed.len());
+    header.write(&mut out_buf);
+    out_buf.extend_from_slice(&message[..offset]);
+    out_buf.extend_from_slice(&compressed);
+
+    Ok(out_buf)
+}
+
+fn decompress_chained(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let original_size: usize;
+
+    let mut in_idx: usize = CHAINED_HEADER_SIZE;
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    if in_buf.len() < CHAINED_HEADER_SIZE {
+        return Err(Error::MemLimit);
+    }
+
+    // Every payload is bounded by what is left of OriginalCompressedSegmentSize.
+    load32le!(original_size, in_buf, 4);
+
+    while in_idx < in_buf.len() {
+        let payload_header = PayloadHeader::parse(&in_buf[in_idx..])?;
+        in_idx += PAYLOAD_HEADER_SIZE;
+
+        let length = payload_header.length as usize;
+        if length > (in_buf.len() - in_idx) {
+            return Err(Error::MemLimit);
+        }
+
+        let payload = &in_buf[in_idx..in_idx + length];
+        in_idx += length;
+
+        let out_max = original_size - out_buf.len();
+
+        match payload_header.compression_algorithm {
+            CompressionAlgorithm::None => {
+                if payload.len() > out_max {
+                    return Err(Error::MemLimit);
+                }
+                out_buf.extend_from_slice(payload);
+            },
+            CompressionAlgorithm::PatternV1 => {
+                let segment = pattern_v1::decompress(payload, out_max)?;
+                out_buf.extend_from_slice(&segment);
+            },
+            algorithm => {
+                let original_payload_size: usize;
+
+                if payload.len() < 4 {
+                    return Err(Error::CorruptedData);
+                }
+
+                load32le!(original_payload_size, payload, 0);
+                if original_payload_size > out_max {
+                    return Err(Error::MemLimit);
+                }
+
+                let segment = decompress_payload(algorithm, &payload[4..], original_payload_size)?;
+                out_buf.extend_from_slice(&segment);
+            },
+        }
+    }
+
+    if out_buf.len() != original_size {
+        return Err(Error::CorruptedData);
+    }
+
+    Ok(out_buf)
+}
+
+pub fn compress_chained(
+    plan: &[ChainedPayload]
+) -> Result<Vec<u8>, Error>
+{
+    let original_size: usize = plan.iter().map(|payload| payload.data.len()).sum();
+    if original_size > u32::MAX as usize {
+        return Err(Error::MemLimit);
+    }
+
+    let mut out_buf: Vec<u8> = Vec::with_capacity(CHAINED_HEADER_SIZE + original_size);
+    out_buf.extend_from_slice(&PROTOCOL_ID.to_le_bytes());
+    out_buf.extend_from_slice(&(original_size as u32).to_le_bytes());
+
+    for payload in plan.iter() {
+        let mut body: Vec<u8> = Vec::new();
+
+        match payload.compression_algorithm {
+            CompressionAlgorithm::None => body.extend_from_slice(payload.data),
+            CompressionAlgorithm::PatternV1 => body = pattern_v1::compress(payload.data)?,
+            algorithm => {
+                body.extend_from_slice(&(payload.data.len() as u32).to_le_bytes());
+                body.extend_from_slice(&compress_payload(algorithm, payload.data)?);
+            },
+        }
+
+        let payload_header = PayloadHeader {
+            compression_algorithm: payload.compression_algorithm,
+            flags: COMPRESSION_FLAG_CHAINED,
+            length: body.len() as u32,
+        };
+
+        payload_header.write(&mut out_buf);
+        out_buf.extend_from_slice(&body);
+    }
+
+    Ok(out_buf)
+}
+
+// Splits message the way Windows builds chained payloads: leading and
+// trailing runs go out as Pattern_V1, the middle with algorithm.
+pub fn plan_chained(
+    message: &[u8],
+    algorithm: CompressionAlgorithm
+) -> Vec<ChainedPayload<'_>>
+{
+    let mut plan: Vec<ChainedPayload> = Vec::with_capacity(3);
+    let runs = pattern_v1::scan(message, pattern_v1::MIN_RUN_LENGTH);
+
+    if runs.leading > 0 {
+        plan.push(ChainedPayload {
+            compression_algorithm: CompressionAlgorithm::PatternV1,
+            data: &message[..runs.leading],
+        });
+    }
+
+    let middle = runs.middle(message);
+    if !middle.is_empty() {
+        plan.push(ChainedPayload {
+            compression_algorithm: algorithm,
+            data: middle,
+        });
+    }
+
+    if runs.trailing > 0 {
+        plan.push(ChainedPayload {
+            compression_algorithm: CompressionAlgorithm::PatternV1,
+            data: &message[message.len() - runs.trailing..],
+        });
+    }
+
+    plan
+}
@@ -0,0 +1,110 @@
+pub use crate::error::Error;
+
+use crate::data;
+
+// WS-Management "xpress" compression (CompressionType xpress): a message is
+// sent as a sequence of blocks, each preceded by its original and compressed
+// sizes (16 bits each). A block whose sizes are equal is stored raw.
+pub const BLOCK_HEADER_SIZE: usize = 4;
+pub const MAX_BLOCK_SIZE:    usize = 0xFFFF;
+
+macro_rules! load16le{
+    ($dst:expr,$src:expr,$idx:expr)=>{
+        {
+            $dst = (u32::from($src[$idx + 1]) << 8
+            | u32::from($src[$idx])) as usize;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block<'a> {
+    pub original_size:   u16,
+    pub compressed_size: u16,
+    pub data:            &'a [u8],
+}
+
+impl Block<'_> {
+    pub fn is_stored(&self) -> bool {
+        self.original_size == self.compressed_size
+    }
+
+    pub fn decompress(&self) -> Result<Vec<u8>, Error> {
+        if self.is_stored() {
+            return Ok(self.data.to_vec());
+        }
+
+        data::decompress_exact(self.data, usize::from(self.original_size))
+    }
+}
+
+pub fn blocks(
+    in_buf: &[u8]
+) -> Result<Vec<Block<'_>>, Error>
+{
+    let mut in_idx: usize = 0;
+    let mut blocks: Vec<Block> = Vec::new();
+
+    while in_idx < in_buf.len() {
+        let original_size:   usize;
+        let compressed_size: usize;
+
+        if (in_buf.len() - in_idx) < BLOCK_HEADER_SIZE {
+            return Err(Error::MemLimit);
+        }
+
+        load16le!(original_size, in_buf, in_idx);
+        load16le!(compressed_size, in_buf, in_idx + 2);
+        in_idx += BLOCK_HEADER_SIZE;
+
+        // A compressed block never grows, it would have been stored raw.
+        if compressed_size > original_size {
+            return Err(Error::CorruptedData);
+        }
+
+        if compressed_size > (in_buf.len() - in_idx) {
+            return Err(Error::MemLimit);
+        }
+
+        blocks.push(Block {
+            original_size: original_size as u16,
+            compressed_size: compressed_size as u16,
+            data: &in_buf[in_idx..in_idx + compressed_size],
+        });
+
+        in_idx += compressed_size;
+    }
+
+    Ok(blocks)
+}
+
+pub fn decompress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::new();
+
+    for block in blocks(in_buf)? {
+        out_buf.extend_from_slice(&block.decompress()?);
+    }
+
+    Ok(out_buf)
+}
+
+pub fn compress(
+    in_buf: &[u8]
+) -> Result<Vec<u8>, Error>
+{
+    let mut out_buf: Vec<u8> = Vec::with_capacity(in_buf.len() + BLOCK_HEADER_SIZE);
+
+    for chunk in in_buf.chunks(MAX_BLOCK_SIZE) {
+        let compressed = data::compress(chunk)?;
+        let body = if compressed.len() < chunk.len() { &compressed[..] } else { chunk };
+
+        out_buf.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        out_buf.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        out_buf.extend_from_slice(body);
+    }
+
+    Ok(out_buf)
+}
@@ -1,5 +1,9 @@
+// The original test vectors and error tests are kept as they were written.
+#![allow(clippy::redundant_static_lifetimes, clippy::assertions_on_constants)]
+
 extern crate lzxpress;
 
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::str;
 
 const TEST_STRING: &'static str = "this is a test. and this is a test too";
@@ -43,6 +47,7 @@ const TEST_LZNT1_DATA1: &'static [u8] = &[
 const TEST_LZNT1_COMPRESSED_DATA: &'static [u8] = include_bytes!("block1.compressed.bin");
 const TEST_LZNT1_UNCOMPRESSED_DATA: &'static [u8] = include_bytes!("block1.uncompressed.bin");
 
+#[cfg(windows)]
 extern "C" {
     fn decompress_lznt1(
        in_buf: *const u8,
@@ -117,8 +122,8 @@ mod tests {
     #[test]
     fn test_compress1() {
         let compressed = lzxpress::data::compress(TEST_STRING.as_bytes()).unwrap();
-        // this come from smb legacy test and implementation, so the output will be different.
-        // assert_eq!(compressed, TEST_DATA);
+        // this come from smb legacy test and implementation, the last flag word is padded with zeros.
+        assert_eq!(lzxpress::data::compress_with(TEST_STRING.as_bytes(), &lzxpress::data::CompressOptions::samba()).unwrap(), TEST_DATA);
 
         let uncompressed = lzxpress::data::decompress(compressed.as_slice()).unwrap();
 
@@ -165,6 +170,43 @@ mod tests {
         assert_eq!(uncompressed, TEST_STRING3.as_bytes());
     }
 
+    #[test]
+    fn test_compress_roundtrip() {
+        // Several long matches sharing length nibbles, then exactly 32 tokens
+        // followed by a flag word made of padding only.
+        let mut data = repeated(b"page five ", 0x1000);
+        data.extend((0..0x1000).map(|i| (i % 251) as u8));
+        let literals: Vec<u8> = (0..32).collect();
+
+        for in_buf in [data, literals].iter() {
+            let compressed = lzxpress::data::compress(in_buf).unwrap();
+            let uncompressed = lzxpress::data::decompress(compressed.as_slice()).unwrap();
+            assert_eq!(&uncompressed, in_buf);
+        }
+    }
+
+    #[test]
+    fn test_decompress_trailing_flag_word() {
+        // 32 literals, then a flag word with nothing left to describe.
+        let literals: Vec<u8> = (0..32).collect();
+
+        for trailing in [[0x00u8; 4], [0xff; 4], [0x00, 0xff, 0x00, 0x80]].iter() {
+            let mut compressed = vec![0u8; 4];
+            compressed.extend_from_slice(&literals);
+            compressed.extend_from_slice(trailing);
+
+            assert_eq!(lzxpress::data::decompress(&compressed).unwrap(), literals);
+            assert_eq!(lzxpress::data::validate(&compressed).unwrap().compressed_size, compressed.len());
+            assert_eq!(lzxpress::data::decompress_consumed(&compressed, usize::MAX).unwrap().1, compressed.len());
+        }
+
+        // A partial flag word is still truncated input.
+        let mut compressed = vec![0u8; 4];
+        compressed.extend_from_slice(&literals);
+        compressed.extend_from_slice(&[0, 0]);
+        assert_eq!(lzxpress::data::decompress(&compressed), Err(lzxpress::error::Error::MemLimit));
+    }
+
     #[test]
     fn test_lznt1_decompress1() {
         let uncompressed = lzxpress::lznt1::decompress(TEST_LZNT1_DATA1).unwrap();
@@ -187,15 +229,78 @@ mod tests {
         assert!(uncompressed.len() == 0x100000, "uncompressed.len = {} (expected len = 0x10000)", uncompressed.len());
         assert_eq!(uncompressed, TEST_LZNT1_UNCOMPRESSED_DATA);
     }
+
+    #[test]
+    fn test_lznt1_decompress_truncated() {
+        let truncated = &TEST_LZNT1_DATA1[..TEST_LZNT1_DATA1.len() - 4];
+        assert!(lzxpress::lznt1::decompress(truncated).is_err());
+    }
+
+    #[test]
+    fn test_lznt1_decompress_same_errors_as_chunks() {
+        use lzxpress::error::Error;
+
+        // Copy token cut by the end of the chunk, then a chunk decoding to 4099 bytes
+        let truncated_token: &[u8] = b"\x02\xb0\x02a\x00";
+        let long_chunk: &[u8] = b"\x03\xb0\x02a\xff\x0f";
+
+        for (in_buf, error) in [(truncated_token, Error::MemLimit), (long_chunk, Error::CorruptedData)].iter() {
+            let chunk = lzxpress::lznt1::chunks(in_buf).next().unwrap().unwrap();
+            assert_eq!(chunk.decompress(), Err(*error));
+            assert_eq!(lzxpress::lznt1::decompress(in_buf), Err(*error));
+            assert_eq!(lzxpress::lznt1::validate(in_buf), Err(*error));
+        }
+    }
  
+    #[test]
+    fn test_lznt1_compress() {
+        let in_buf = &TEST_LZNT1_UNCOMPRESSED_DATA[..0x10000];
+        let compressed = lzxpress::lznt1::compress(in_buf).unwrap();
+        let uncompressed = lzxpress::lznt1::decompress(&compressed).unwrap();
+
+        assert!(compressed.len() < in_buf.len());
+        assert_eq!(uncompressed, in_buf);
+    }
+
+    #[test]
+    fn test_huffman_compress() {
+        let compressed = lzxpress::huffman::compress(TEST_LZNT1_UNCOMPRESSED_DATA).unwrap();
+        let uncompressed = lzxpress::huffman::decompress(&compressed, TEST_LZNT1_UNCOMPRESSED_DATA.len()).unwrap();
+
+        assert!(compressed.len() < TEST_LZNT1_UNCOMPRESSED_DATA.len());
+        assert_eq!(uncompressed, TEST_LZNT1_UNCOMPRESSED_DATA);
+
+        let compressed = lzxpress::huffman::compress(&[]).unwrap();
+        assert_eq!(lzxpress::huffman::decompress(&compressed, 0).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_huffman_ms_xca_example() {
+        // [MS-XCA] 3.2: TEST_STRING3 with LZ77+Huffman. 'a' and 'b' are coded
+        // on 3 bits, 'c', the end of stream and the match of offset 3 and
+        // length 297 on 2 bits.
+        let mut expected = vec![0u8; 256];
+        expected[0x30] = 0x30;
+        expected[0x31] = 0x23;
+        expected[0x80] = 0x02;
+        expected[0x8f] = 0x20;
+        expected.extend_from_slice(&[0xa8, 0xdc, 0x00, 0x00, 0xff, 0x26, 0x01]);
+
+        let uncompressed = lzxpress::huffman::decompress(&expected, TEST_STRING3.len()).unwrap();
+        assert_eq!(uncompressed, TEST_STRING3.as_bytes());
+
+        let compressed = lzxpress::huffman::compress(TEST_STRING3.as_bytes()).unwrap();
+        assert_eq!(compressed, expected);
+    }
+
     #[test]
     #[cfg(windows)]
     fn test_lznt1_decompress_vs_rtl() {
         unsafe {
             let uncompressed_local = lzxpress::lznt1::decompress(TEST_LZNT1_COMPRESSED_DATA).unwrap();
 
-            let compression_format_lznt1 = 0x0002 as u16;
-            let compression_engine_standard = 0x0000 as u16;
+            let compression_format_lznt1 = lzxpress::rtl::COMPRESSION_FORMAT_LZNT1;
+            let compression_engine_standard = lzxpress::rtl::COMPRESSION_ENGINE_STANDARD;
             use ntapi::ntrtl::{RtlDecompressBuffer};
 
             let mut mutable_compressed_data = TEST_LZNT1_COMPRESSED_DATA.to_vec();
@@ -276,4 +381,1182 @@ mod tests {
         }
 
     }
+
+    // Shared fixtures
+
+    // xorshift32 output, does not compress
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    // pattern repeated up to len bytes
+    fn repeated(pattern: &[u8], len: usize) -> Vec<u8> {
+        pattern.iter().cycle().take(len).copied().collect()
+    }
+
+    // Compressible text without long repeats: words picked by noise(seed, ..).
+    // Tagged words are followed by their picking byte in hex, which leaves
+    // literals all along the compressed stream.
+    fn words(seed: u32, len: usize, tagged: bool) -> Vec<u8> {
+        const WORDS: [&str; 8] = ["this ", "is ", "a ", "test ", "and ", "too ", "compressed ", "stream "];
+        noise(seed, len).iter().flat_map(|b| {
+            let word = WORDS[*b as usize % WORDS.len()];
+            if tagged { format!("{}{:02x} ", word, b) } else { word.to_string() }.into_bytes()
+        }).take(len).collect()
+    }
+
+    fn hiberfil_header(first_table_page: u64, num_pages_for_loader: u64) -> Vec<u8> {
+        let mut page = vec![0u8; lzxpress::hiberfil::PAGE_SIZE];
+        page[0..4].copy_from_slice(b"HIBR");
+        page[0x18..0x1c].copy_from_slice(&0x1000u32.to_le_bytes());
+        page[0x58..0x60].copy_from_slice(&num_pages_for_loader.to_le_bytes());
+        page[0x68..0x70].copy_from_slice(&first_table_page.to_le_bytes());
+        page
+    }
+
+    fn hiberfil_pages() -> Vec<u8> {
+        let mut pages = repeated(b"page five ", 0x1000);
+        pages.extend((0..0x1000).map(|i| (i % 251) as u8));
+        pages
+    }
+
+    // Windows 8+ image with a single compression set holding page frames 3 and 4
+    fn hiberfil_win8_image(compressed: &[u8], huffman: bool) -> Vec<u8> {
+        let mut image = hiberfil_header(1, 2);
+
+        let set_header = (1 | (compressed.len() << 8) | ((huffman as usize) << 31)) as u32;
+        image.extend_from_slice(&set_header.to_le_bytes());
+        image.extend_from_slice(&((3u64 << 4) | 1).to_le_bytes());
+        image.extend_from_slice(compressed);
+        image
+    }
+
+    #[test]
+    fn test_hiberfil_win7() {
+        let pages = hiberfil_pages();
+        let compressed = lzxpress::data::compress(&pages).unwrap();
+        let size = (compressed.len() + 7) & !7;
+
+        let mut image = hiberfil_header(1, 0);
+
+        // PO_MEMORY_RANGE_ARRAY covering page frames 5 and 6
+        let mut table = vec![0u8; 0x1000];
+        table[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+        table[0x28..0x30].copy_from_slice(&5u64.to_le_bytes());
+        table[0x30..0x38].copy_from_slice(&7u64.to_le_bytes());
+        image.extend_from_slice(&table);
+
+        let encoded_size = ((size - 1) << 10) as u32;
+        image.extend_from_slice(b"\x81\x81xpress");
+        image.push(1);
+        image.extend_from_slice(&encoded_size.to_le_bytes()[1..4]);
+        image.resize(image.len() + 0x14, 0);
+        image.extend_from_slice(&compressed);
+        image.resize(image.len() + size - compressed.len(), 0);
+
+        let mut hiberfil = lzxpress::hiberfil::HiberFile::new(Cursor::new(image)).unwrap();
+        assert_eq!(hiberfil.layout(), lzxpress::hiberfil::Layout::Win7);
+        assert_eq!(hiberfil.page_frames().collect::<Vec<u64>>(), vec![5, 6]);
+        assert_eq!(hiberfil.len(), 7 * 0x1000);
+
+        let mut memory = vec![0u8; pages.len()];
+        hiberfil.seek(SeekFrom::Start(5 * 0x1000)).unwrap();
+        hiberfil.read_exact(&mut memory).unwrap();
+        assert_eq!(memory, pages);
+
+        let mut missing = vec![0xffu8; 0x1000];
+        hiberfil.seek(SeekFrom::Start(0)).unwrap();
+        hiberfil.read_exact(&mut missing).unwrap();
+        assert!(missing.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_hiberfil_win8() {
+        let pages = hiberfil_pages();
+        let compressed = lzxpress::data::compress(&pages).unwrap();
+
+        let mut hiberfil = lzxpress::hiberfil::HiberFile::new(Cursor::new(hiberfil_win8_image(&compressed, false))).unwrap();
+        assert_eq!(hiberfil.layout(), lzxpress::hiberfil::Layout::Win8);
+        assert_eq!(hiberfil.page_sets()[0].compression, lzxpress::hiberfil::Compression::Xpress);
+
+        let mut memory = Vec::new();
+        hiberfil.seek(SeekFrom::Start(3 * 0x1000)).unwrap();
+        hiberfil.read_to_end(&mut memory).unwrap();
+        assert_eq!(memory, pages);
+    }
+
+    #[test]
+    fn test_hiberfil_bad_signature() {
+        let mut image = hiberfil_header(1, 0);
+        image[0..4].copy_from_slice(b"\0\0\0\0");
+
+        assert!(lzxpress::hiberfil::HiberFile::new(Cursor::new(image)).is_err());
+    }
+
+    #[test]
+    fn test_hiberfil_offset_overflow() {
+        let image = hiberfil_header(u64::MAX, 0);
+        let err = lzxpress::hiberfil::HiberFile::new(Cursor::new(image)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let image = hiberfil_header(u64::MAX / 0x1000, 0);
+        let err = lzxpress::hiberfil::HiberFile::new(Cursor::new(image)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Page frame near the top of the descriptor range
+        let pages = hiberfil_pages();
+        let mut image = hiberfil_win8_image(&pages, false);
+        let descriptor = ((u64::MAX >> 4) << 4) | 1;
+        image[0x1004..0x100c].copy_from_slice(&descriptor.to_le_bytes());
+        let err = lzxpress::hiberfil::HiberFile::new(Cursor::new(image)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_memstore_decode_pages() {
+        use lzxpress::memstore::{decode_pages, PageRecord};
+
+        let page = repeated(b"compressed store page ", 0x1000);
+        let raw_page: Vec<u8> = (0..0x1000).map(|i| (i * 7 % 256) as u8).collect();
+        let compressed = lzxpress::data::compress(&page).unwrap();
+        let too_large = lzxpress::data::compress(&[0u8; 0x2000]).unwrap();
+
+        // Records are 16 bytes aligned in the store, the tail is padding.
+        let mut region = compressed.clone();
+        region.resize((region.len() + 15) & !15, 0);
+        let raw_offset = region.len();
+        region.extend_from_slice(&raw_page);
+        let too_large_offset = region.len();
+        region.extend_from_slice(&too_large);
+
+        let records = [
+            PageRecord { offset: 0, compressed_size: (compressed.len() + 15) & !15 },
+            PageRecord { offset: raw_offset, compressed_size: 0x1000 },
+            PageRecord { offset: too_large_offset, compressed_size: too_large.len() },
+            PageRecord { offset: region.len() - 8, compressed_size: 16 },
+        ];
+
+        let store_pages = decode_pages(&region, &records);
+        assert_eq!(store_pages.pages[0].as_ref(), Some(&page));
+        assert_eq!(store_pages.pages[1].as_ref(), Some(&raw_page));
+        assert!(store_pages.pages[2].is_none() && store_pages.pages[3].is_none());
+
+        assert_eq!(store_pages.failures.len(), 2);
+        assert_eq!(store_pages.failures[0].index, 2);
+        assert_eq!(store_pages.failures[0].error, lzxpress::error::Error::MemLimit);
+        assert_eq!(store_pages.failures[1].index, 3);
+    }
+
+    // LZ77+Huffman stream where every literal is coded on 8 bits and no match is used.
+    fn huffman_literals(in_buf: &[u8]) -> Vec<u8> {
+        let mut out_buf = vec![0x88u8; 128];
+        out_buf.resize(256, 0);
+
+        for pair in in_buf.chunks(2) {
+            out_buf.push(*pair.get(1).unwrap_or(&0));
+            out_buf.push(pair[0]);
+        }
+        out_buf.extend_from_slice(&[0, 0, 0, 0]);
+        out_buf
+    }
+
+    #[test]
+    fn test_huffman_decompress_literals() {
+        let compressed = huffman_literals(TEST_STRING.as_bytes());
+        let uncompressed = lzxpress::huffman::decompress(&compressed, TEST_STRING.len()).unwrap();
+
+        assert_eq!(uncompressed, TEST_STRING.as_bytes());
+    }
+
+    #[test]
+    fn test_huffman_decompress_bad_table() {
+        let compressed = [0u8; 260];
+        let result = lzxpress::huffman::decompress(&compressed, 1);
+
+        assert_eq!(result, Err(lzxpress::error::Error::CorruptedData));
+
+        // The size is not trusted for the preallocation.
+        let result = lzxpress::huffman::decompress(&compressed, usize::MAX);
+        assert_eq!(result, Err(lzxpress::error::Error::CorruptedData));
+    }
+
+    #[test]
+    fn test_huffman_decompress_truncated() {
+        let in_buf = words(0x0bad_f00d, 20000, true);
+        let compressed = lzxpress::huffman::compress(&in_buf).unwrap();
+
+        for cut in [257, 260, 300, compressed.len() / 2, compressed.len() - 8] {
+            let result = lzxpress::huffman::decompress(&compressed[..cut], in_buf.len());
+            assert_eq!(result, Err(lzxpress::error::Error::CorruptedData), "cut at {}", cut);
+        }
+
+        // Only the final padding word may be missing
+        let uncompressed = lzxpress::huffman::decompress(&compressed[..compressed.len() - 2], in_buf.len()).unwrap();
+        assert_eq!(uncompressed, in_buf);
+
+        // Decoding stops at the EOF symbol
+        let uncompressed = lzxpress::huffman::decompress(&compressed, in_buf.len() + 5000).unwrap();
+        assert_eq!(uncompressed, in_buf);
+    }
+
+    #[test]
+    fn test_hiberfil_win8_huffman() {
+        let pages = hiberfil_pages();
+
+        let mut hiberfil = lzxpress::hiberfil::HiberFile::new(Cursor::new(hiberfil_win8_image(&huffman_literals(&pages), true))).unwrap();
+        assert_eq!(hiberfil.page_sets()[0].compression, lzxpress::hiberfil::Compression::XpressHuffman);
+
+        let mut memory = Vec::new();
+        hiberfil.seek(SeekFrom::Start(3 * 0x1000)).unwrap();
+        hiberfil.read_to_end(&mut memory).unwrap();
+        assert_eq!(memory, pages);
+    }
+
+    fn crc32(in_buf: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for b in in_buf {
+            crc ^= u32::from(*b);
+            for _i in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    fn prefetch_file(body: &[u8], with_checksum: bool) -> Vec<u8> {
+        let mut file = b"MAM\x04".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        if with_checksum {
+            file[3] |= 0x80;
+            file.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        file.extend_from_slice(&huffman_literals(body));
+        if with_checksum {
+            let crc = crc32(&file);
+            file[8..12].copy_from_slice(&crc.to_le_bytes());
+        }
+        file
+    }
+
+    #[test]
+    fn test_prefetch_decompress() {
+        let mut body = 30u32.to_le_bytes().to_vec();
+        body.extend_from_slice(b"SCCA");
+        body.extend_from_slice(b"\x11\x00\x00\x00NOTEPAD.EXE");
+
+        for with_checksum in [false, true].iter() {
+            let file = prefetch_file(&body, *with_checksum);
+            assert!(lzxpress::prefetch::is_compressed(&file));
+
+            let header = lzxpress::prefetch::MamHeader::parse(&file).unwrap();
+            assert_eq!(header.format, lzxpress::prefetch::FORMAT_XPRESS_HUFF);
+            assert_eq!(header.checksum.is_some(), *with_checksum);
+            assert_eq!(lzxpress::prefetch::decompress(&file).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn test_prefetch_errors() {
+        use lzxpress::error::Error;
+
+        let mut body = 30u32.to_le_bytes().to_vec();
+        body.extend_from_slice(b"SCCA");
+
+        let mut file = prefetch_file(&body, true);
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        assert_eq!(lzxpress::prefetch::decompress(&file), Err(Error::ChecksumMismatch));
+
+        let file = prefetch_file(b"\x1e\x00\x00\x00MAM!", false);
+        assert_eq!(lzxpress::prefetch::decompress(&file), Err(Error::InvalidSignature));
+
+        let mut file = prefetch_file(&body, false);
+        file[3] = 0x05;
+        assert_eq!(lzxpress::prefetch::decompress(&file), Err(Error::UnsupportedAlgorithm));
+        file[3] = 0x84 | 0x10;
+        assert_eq!(lzxpress::prefetch::decompress(&file), Err(Error::CorruptedData));
+        assert_eq!(lzxpress::prefetch::decompress(&body), Err(Error::InvalidSignature));
+    }
+
+    #[test]
+    fn test_smb2_unchained() {
+        use lzxpress::smb2::{self, CompressionAlgorithm};
+
+        let mut message = b"\xfeSMB".to_vec();
+        message.resize(0x40, 0);
+        message.extend(TEST_STRING3.as_bytes());
+
+        let algorithms = [CompressionAlgorithm::Lznt1, CompressionAlgorithm::Lz77, CompressionAlgorithm::Lz77Huffman];
+        for algorithm in algorithms.iter() {
+            let compressed = smb2::compress(&message, *algorithm, 0x40).unwrap();
+            let header = smb2::TransformHeader::parse(&compressed).unwrap();
+
+            assert_eq!(header.compression_algorithm, *algorithm);
+            assert_eq!(header.offset, 0x40);
+            assert_eq!(header.original_compressed_segment_size as usize, TEST_STRING3.len());
+            assert_eq!(&compressed[16..0x50], &message[..0x40]);
+            assert_eq!(smb2::decompress(&compressed).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_smb2_errors() {
+        use lzxpress::error::Error;
+        use lzxpress::smb2::{self, CompressionAlgorithm};
+
+        let compressed = smb2::compress(TEST_STRING.as_bytes(), CompressionAlgorithm::Lz77, 0).unwrap();
+
+        let mut bad_size = compressed.clone();
+        bad_size[4] += 1;
+        assert_eq!(smb2::decompress(&bad_size), Err(Error::CorruptedData));
+
+        let mut bad_offset = compressed.clone();
+        bad_offset[12..16].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(smb2::decompress(&bad_offset), Err(Error::MemLimit));
+
+        let mut bad_protocol = compressed.clone();
+        bad_protocol[0] = 0xfe;
+        assert_eq!(smb2::decompress(&bad_protocol), Err(Error::InvalidSignature));
+
+        let mut bad_algorithm = compressed;
+        bad_algorithm[8] = 0x10;
+        assert_eq!(smb2::decompress(&bad_algorithm), Err(Error::UnsupportedAlgorithm));
+
+        let lznt1 = lzxpress::lznt1::compress(TEST_STRING.as_bytes()).unwrap();
+        assert_eq!(smb2::decompress_payload(CompressionAlgorithm::Lznt1, &lznt1, usize::MAX), Err(Error::CorruptedData));
+    }
+
+    #[test]
+    fn test_smb2_chained() {
+        use lzxpress::smb2::{self, ChainedPayload, CompressionAlgorithm};
+
+        let header = [0xfe, b'S', b'M', b'B', 0x40, 0x00];
+        let zeroes = [0u8; 0x200];
+        let plan = [
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::None, data: &header },
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::Lz77, data: TEST_STRING3.as_bytes() },
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::Lz77Huffman, data: TEST_STRING.as_bytes() },
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::PatternV1, data: &zeroes },
+        ];
+
+        let message: Vec<u8> = plan.iter().flat_map(|payload| payload.data.iter().copied()).collect();
+        let compressed = smb2::compress_chained(&plan).unwrap();
+
+        let header = smb2::TransformHeader::parse(&compressed).unwrap();
+        assert_eq!(header.flags, smb2::COMPRESSION_FLAG_CHAINED);
+        assert_eq!(header.original_compressed_segment_size as usize, message.len());
+        assert_eq!(smb2::decompress(&compressed).unwrap(), message);
+
+        // The Pattern_V1 payload is the last 16 bytes.
+        let pattern_off = compressed.len() - 16;
+        let payload_header = smb2::PayloadHeader::parse(&compressed[pattern_off..]).unwrap();
+        assert_eq!(payload_header.compression_algorithm, CompressionAlgorithm::PatternV1);
+        assert_eq!(payload_header.length, 8);
+    }
+
+    #[test]
+    fn test_smb2_chained_limits() {
+        use lzxpress::error::Error;
+        use lzxpress::smb2::{self, ChainedPayload, CompressionAlgorithm};
+
+        let zeroes = [0u8; 0x100];
+        let plan = [
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::Lz77, data: TEST_STRING.as_bytes() },
+            ChainedPayload { compression_algorithm: CompressionAlgorithm::PatternV1, data: &zeroes },
+        ];
+        let compressed = smb2::compress_chained(&plan).unwrap();
+
+        // OriginalCompressedSegmentSize too small for the Pattern_V1 repetitions
+        let mut too_small = compressed.clone();
+        too_small[4..8].copy_from_slice(&((TEST_STRING.len() + 0x10) as u32).to_le_bytes());
+        assert_eq!(smb2::decompress(&too_small), Err(Error::MemLimit));
+
+        // ... or for the OriginalPayloadSize of the first payload
+        too_small[4..8].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(smb2::decompress(&too_small), Err(Error::MemLimit));
+
+        let mut too_large = compressed;
+        too_large[4..8].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(smb2::decompress(&too_large), Err(Error::CorruptedData));
+
+        let not_a_run = [ChainedPayload { compression_algorithm: CompressionAlgorithm::PatternV1, data: b"ab" }];
+        assert!(smb2::compress_chained(&not_a_run).is_err());
+    }
+
+    #[test]
+    fn test_pattern_v1() {
+        use lzxpress::error::Error;
+        use lzxpress::pattern_v1;
+
+        let compressed = pattern_v1::compress(&[0xaa; 300]).unwrap();
+        assert_eq!(compressed, [0xaa, 0, 0, 0, 0x2c, 0x01, 0, 0]);
+        assert_eq!(pattern_v1::decompress(&compressed, 300).unwrap(), vec![0xaa; 300]);
+        assert_eq!(pattern_v1::decompress(&compressed, 299), Err(Error::MemLimit));
+        assert_eq!(pattern_v1::compress(b"aab"), Err(Error::CorruptedData));
+
+        let mut message = vec![0u8; 64];
+        message.extend_from_slice(TEST_STRING.as_bytes());
+        message.extend_from_slice(&[0xff; 40]);
+
+        let runs = pattern_v1::scan(&message, pattern_v1::MIN_RUN_LENGTH);
+        assert_eq!(runs, pattern_v1::Runs { leading: 64, trailing: 40 });
+        assert_eq!(runs.middle(&message), TEST_STRING.as_bytes());
+
+        let runs = pattern_v1::scan(&message[60..message.len() - 20], pattern_v1::MIN_RUN_LENGTH);
+        assert_eq!(runs, pattern_v1::Runs::default());
+        assert_eq!(pattern_v1::scan(&[7; 40], pattern_v1::MIN_RUN_LENGTH).leading, 40);
+    }
+
+    #[test]
+    fn test_smb2_plan_chained() {
+        use lzxpress::smb2::{self, CompressionAlgorithm};
+
+        let mut message = vec![0u8; 0x100];
+        message.extend_from_slice(TEST_STRING3.as_bytes());
+
+        let plan = smb2::plan_chained(&message, CompressionAlgorithm::Lz77);
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].compression_algorithm, CompressionAlgorithm::PatternV1);
+        assert_eq!(plan[1].data, TEST_STRING3.as_bytes());
+
+        let compressed = smb2::compress_chained(&plan).unwrap();
+        assert_eq!(smb2::decompress(&compressed).unwrap(), message);
+    }
+
+    fn drsr_block(uncompressed_size: usize, data: &[u8]) -> Vec<u8> {
+        let mut block = (uncompressed_size as u32).to_le_bytes().to_vec();
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block.extend_from_slice(data);
+        block
+    }
+
+    #[test]
+    fn test_drsr_decompress() {
+        use lzxpress::drsr::{self, CompressionAlgorithm};
+
+        let mut blob = drsr_block(TEST_STRING.len(), TEST_DATA);
+        blob.extend(drsr_block(TEST_STRING2.len(), TEST_STRING2.as_bytes()));
+        blob.extend(drsr_block(TEST_STRING3.len(), TEST_DATA3));
+        let expected = [TEST_STRING, TEST_STRING2, TEST_STRING3].concat();
+
+        let blocks = drsr::blocks(&blob).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert!(!blocks[0].is_stored() && blocks[1].is_stored());
+
+        let uncompressed = drsr::decompress(&blob, CompressionAlgorithm::Xpress, expected.len()).unwrap();
+        assert_eq!(uncompressed, expected.as_bytes());
+    }
+
+    #[test]
+    fn test_drsr_errors() {
+        use lzxpress::drsr::{self, CompressionAlgorithm};
+        use lzxpress::error::Error;
+
+        // cbUncompressedSize of the block does not match the data
+        let blob = drsr_block(TEST_STRING.len() - 1, TEST_DATA);
+        assert_eq!(drsr::decompress(&blob, CompressionAlgorithm::Xpress, TEST_STRING.len() - 1), Err(Error::MemLimit));
+        let blob = drsr_block(TEST_STRING.len() + 1, TEST_DATA);
+        assert_eq!(drsr::decompress(&blob, CompressionAlgorithm::Xpress, TEST_STRING.len() + 1), Err(Error::CorruptedData));
+
+        let blob = drsr_block(TEST_STRING.len(), TEST_DATA);
+        assert_eq!(drsr::decompress(&blob, CompressionAlgorithm::Xpress, 4), Err(Error::MemLimit));
+        assert_eq!(drsr::decompress(&blob, CompressionAlgorithm::Mszip, TEST_STRING.len()), Err(Error::UnsupportedAlgorithm));
+        assert_eq!(drsr::blocks(&blob[..20]), Err(Error::MemLimit));
+    }
+
+    #[test]
+    fn test_oxcrpc_chain() {
+        use lzxpress::oxcrpc;
+
+        let rop1 = TEST_STRING3.as_bytes();
+        let rop2 = TEST_STRING2.as_bytes();
+        let flags = oxcrpc::FLAG_COMPRESSED | oxcrpc::FLAG_XOR_MAGIC;
+        let chain = oxcrpc::compress_buffers(&[rop1, rop2], flags).unwrap();
+
+        let header = oxcrpc::RpcHeaderExt::parse(&chain).unwrap();
+        assert_eq!(header.flags, flags);
+        assert_eq!(header.size_actual as usize, rop1.len());
+        assert_eq!(&chain[8..8 + TEST_DATA3.len()], TEST_DATA3.iter().map(|b| b ^ 0xA5).collect::<Vec<u8>>().as_slice());
+
+        // The second payload does not compress, it is only obfuscated.
+        let header = oxcrpc::RpcHeaderExt::parse(&chain[8 + header.size as usize..]).unwrap();
+        assert_eq!(header.flags, oxcrpc::FLAG_XOR_MAGIC | oxcrpc::FLAG_LAST);
+
+        assert_eq!(oxcrpc::decompress_buffers(&chain).unwrap(), vec![rop1.to_vec(), rop2.to_vec()]);
+
+        let large = repeated(TEST_STRING.as_bytes(), 0x18000);
+        let chain = oxcrpc::compress(&large, oxcrpc::FLAG_COMPRESSED).unwrap();
+        assert_eq!(oxcrpc::decompress(&chain).unwrap(), large);
+    }
+
+    #[test]
+    fn test_oxcrpc_errors() {
+        use lzxpress::error::Error;
+        use lzxpress::oxcrpc;
+
+        let chain = oxcrpc::compress_buffers(&[TEST_STRING3.as_bytes()], oxcrpc::FLAG_COMPRESSED).unwrap();
+
+        let mut not_last = chain.clone();
+        not_last[2] &= !(oxcrpc::FLAG_LAST as u8);
+        assert_eq!(oxcrpc::decompress(&not_last), Err(Error::CorruptedData));
+
+        let mut bad_version = chain.clone();
+        bad_version[0] = 1;
+        assert_eq!(oxcrpc::decompress(&bad_version), Err(Error::InvalidSignature));
+
+        assert_eq!(oxcrpc::decompress(&chain[..chain.len() - 1]), Err(Error::MemLimit));
+    }
+
+    #[test]
+    fn test_wsman() {
+        use lzxpress::error::Error;
+        use lzxpress::wsman;
+
+        let message = repeated(TEST_STRING.as_bytes(), 0x18000);
+        let compressed = wsman::compress(&message).unwrap();
+        assert!(compressed.len() < message.len());
+
+        let blocks = wsman::blocks(&compressed).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].original_size as usize, wsman::MAX_BLOCK_SIZE);
+        assert!(!blocks[0].is_stored());
+        assert_eq!(wsman::decompress(&compressed).unwrap(), message);
+
+        // Too short to shrink, stored raw.
+        let compressed = wsman::compress(b"abc").unwrap();
+        assert_eq!(compressed, b"\x03\x00\x03\x00abc");
+        assert_eq!(wsman::decompress(&compressed).unwrap(), b"abc");
+
+        assert_eq!(wsman::decompress(&compressed[..5]), Err(Error::MemLimit));
+        assert_eq!(wsman::decompress(b"\x03\x00\x04\x00abcd"), Err(Error::CorruptedData));
+    }
+
+    #[test]
+    fn test_ovba() {
+        use lzxpress::error::Error;
+        use lzxpress::ovba;
+
+        // [MS-OVBA] 3.2.1 and 3.2.2
+        let compressed1: &[u8] = b"\x01\x19\xb0\x00abcdefgh\x00ijklmnop\x00qrstuv.";
+        let compressed2: &[u8] = b"\x01\x2f\xb0\x00\x23\x61\x61\x61\x62\x63\x64\x65\x82\x66\x00\x70\x61\x67\x68\x69\x6a\x01\x38\x08\x61\x6b\x6c\x00\x30\x6d\x6e\x6f\x70\x06\x71\x02\x70\x04\x10\x72\x73\x74\x75\x76\x10\x77\x78\x79\x7a\x00\x3c";
+        let uncompressed2: &[u8] = b"#aaabcdefaaaaghijaaaaaklaaamnopqaaaaaaaaaaaarstuvwxyzaaa";
+
+        assert_eq!(ovba::decompress(compressed1).unwrap(), b"abcdefghijklmnopqrstuv.");
+        assert_eq!(ovba::decompress(compressed2).unwrap(), uncompressed2);
+        assert_eq!(ovba::compress(b"abcdefghijklmnopqrstuv.").unwrap(), compressed1);
+        assert_eq!(ovba::decompress(&ovba::compress(uncompressed2).unwrap()).unwrap(), uncompressed2);
+
+        let in_buf = repeated(TEST_STRING.as_bytes(), 0x2800);
+        assert_eq!(ovba::decompress(&ovba::compress(&in_buf).unwrap()).unwrap(), in_buf);
+
+        // A chunk that does not fit in 4096 bytes once compressed is stored
+        // raw, padded to 4096 bytes.
+        let random = noise(0x1234_5678, 8000);
+        let compressed = ovba::compress(&random).unwrap();
+        assert_eq!(&compressed[..3], b"\x01\xff\x3f");
+        assert_eq!(compressed.len(), 1 + 2 * (2 + 4096));
+
+        // The padding of the last raw chunk comes back with the data.
+        let uncompressed = ovba::decompress(&compressed).unwrap();
+        assert_eq!(uncompressed.len(), 2 * 4096);
+        assert_eq!(uncompressed[..random.len()], random[..]);
+        assert!(uncompressed[random.len()..].iter().all(|b| *b == 0));
+
+        assert_eq!(ovba::decompress(&compressed1[1..]), Err(Error::InvalidSignature));
+        assert_eq!(ovba::decompress(b"\x01\x02\xb0\x01\x00\x00"), Err(Error::CorruptedData));
+    }
+
+    #[test]
+    fn test_compressapi() {
+        use lzxpress::compressapi::{self, Algorithm};
+        use lzxpress::error::Error;
+
+        let in_buf = repeated(TEST_STRING.as_bytes(), compressapi::DEFAULT_BLOCK_SIZE + 0x1000);
+
+        for algorithm in [Algorithm::Xpress, Algorithm::XpressHuff] {
+            let compressed = compressapi::compress(&in_buf, algorithm).unwrap();
+            assert_eq!(&compressed[..6], &[0x0a, 0x51, 0xe5, 0xc0, 0x18, algorithm.to_u8()]);
+
+            let header = compressapi::Header::parse(&compressed).unwrap();
+            assert_eq!(header.uncompressed_size as usize, in_buf.len());
+            assert_eq!(compressapi::decompress(&compressed).unwrap(), in_buf);
+            assert_eq!(compressapi::decompress(&compressed[..compressed.len() - 1]), Err(Error::MemLimit));
+        }
+
+        // Stored raw
+        let compressed = compressapi::compress(b"abc", Algorithm::Xpress).unwrap();
+        assert_eq!(&compressed[compressapi::HEADER_SIZE..], b"\x03\x00\x00\x00abc");
+        assert_eq!(compressapi::decompress(&compressed).unwrap(), b"abc");
+
+        assert_eq!(compressapi::compress(b"abc", Algorithm::Lzms), Err(Error::UnsupportedAlgorithm));
+
+        let mut mszip = compressed.clone();
+        mszip[5] = Algorithm::Mszip.to_u8();
+        mszip[compressapi::HEADER_SIZE] = 2;
+        assert_eq!(compressapi::decompress(&mszip), Err(Error::UnsupportedAlgorithm));
+
+        let mut bad_magic = compressed.clone();
+        bad_magic[0] = 0;
+        assert_eq!(compressapi::decompress(&bad_magic), Err(Error::InvalidSignature));
+
+        // Sizes the blocks cannot reach
+        let mut too_large = compressed.clone();
+        too_large[8..16].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(compressapi::decompress(&too_large), Err(Error::MemLimit));
+
+        let mut too_large = compressed;
+        too_large[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        too_large[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(compressapi::decompress(&too_large).is_err());
+    }
+
+    #[test]
+    fn test_rtl() {
+        use lzxpress::error::Error;
+        use lzxpress::rtl;
+
+        let in_buf = TEST_STRING.as_bytes();
+
+        for format in [rtl::COMPRESSION_FORMAT_LZNT1, rtl::COMPRESSION_FORMAT_XPRESS, rtl::COMPRESSION_FORMAT_XPRESS_HUFF] {
+            let format_engine = format | rtl::COMPRESSION_ENGINE_MAXIMUM;
+            assert_eq!(rtl::get_compression_work_space_size(format_engine), Ok((0, 0)));
+
+            let mut compressed = vec![0u8; 0x1000];
+            let compressed_len = rtl::compress_buffer(format_engine, in_buf, &mut compressed).unwrap();
+
+            let mut uncompressed = vec![0u8; in_buf.len()];
+            assert_eq!(rtl::decompress_buffer(format_engine, &compressed[..compressed_len], &mut uncompressed), Ok(in_buf.len()));
+            assert_eq!(uncompressed, in_buf);
+
+            let mut too_small = vec![0u8; compressed_len - 1];
+            let err = rtl::compress_buffer(format_engine, in_buf, &mut too_small).unwrap_err();
+            assert_eq!(rtl::ntstatus(err), rtl::STATUS_BUFFER_TOO_SMALL);
+
+            // XPRESS_HUFF stops once out_buf is full
+            if format != rtl::COMPRESSION_FORMAT_XPRESS_HUFF {
+                let mut too_small = vec![0u8; in_buf.len() - 1];
+                let err = rtl::decompress_buffer(format_engine, &compressed[..compressed_len], &mut too_small).unwrap_err();
+                assert_eq!(rtl::ntstatus(err), rtl::STATUS_BUFFER_TOO_SMALL);
+            }
+
+            let err = rtl::decompress_buffer(format_engine, &compressed[..2], &mut uncompressed).unwrap_err();
+            assert_eq!(rtl::ntstatus(err), rtl::STATUS_BAD_COMPRESSION_BUFFER);
+        }
+
+        let mut uncompressed = vec![0u8; 0x1000];
+        assert_eq!(rtl::decompress_buffer(rtl::COMPRESSION_FORMAT_LZNT1, TEST_LZNT1_DATA1, &mut uncompressed), Ok(TEST_LZNT1_STRING1.len()));
+
+        // A short out_buf is reported the same way wherever the cut falls
+        let compressed = lzxpress::data::compress(&repeated(b"abcdefgh", 24)).unwrap();
+        for out_len in [8, 10, 23] {
+            let err = rtl::decompress_buffer(rtl::COMPRESSION_FORMAT_XPRESS, &compressed, &mut uncompressed[..out_len]).unwrap_err();
+            assert_eq!(rtl::ntstatus(err), rtl::STATUS_BUFFER_TOO_SMALL);
+        }
+        assert_eq!(rtl::decompress_buffer(rtl::COMPRESSION_FORMAT_XPRESS, &compressed, &mut uncompressed[..24]), Ok(24));
+
+        for format in [rtl::COMPRESSION_FORMAT_NONE, rtl::COMPRESSION_FORMAT_DEFAULT] {
+            let err = rtl::decompress_buffer(format, TEST_DATA, &mut uncompressed).unwrap_err();
+            assert_eq!(err, Error::InvalidParameter);
+            assert_eq!(rtl::ntstatus(err), rtl::STATUS_INVALID_PARAMETER);
+        }
+        let err = rtl::decompress_buffer(0x0005, TEST_DATA, &mut uncompressed).unwrap_err();
+        assert_eq!(rtl::ntstatus(err), rtl::STATUS_UNSUPPORTED_COMPRESSION);
+        assert_eq!(rtl::get_compression_work_space_size(rtl::COMPRESSION_FORMAT_LZNT1 | 0x0400), Err(Error::UnsupportedAlgorithm));
+        assert_eq!(rtl::ntstatus(Error::CorruptedData), rtl::STATUS_BAD_COMPRESSION_BUFFER);
+    }
+
+    #[test]
+    fn test_lznt1_decompress_range() {
+        let in_buf = repeated(TEST_STRING.as_bytes(), 0x2800);
+        let compressed = lzxpress::lznt1::compress(&in_buf).unwrap();
+
+        for (offset, len) in [(0, 0x10), (0x0ff0, 0x20), (0x1000, 0x1000), (0x27f0, 0x100), (0x3000, 0x10), (0, 0x2800)] {
+            let range = lzxpress::lznt1::decompress_range(&compressed, offset, len).unwrap();
+            let end = std::cmp::min(in_buf.len(), offset + len);
+            assert_eq!(range, in_buf[std::cmp::min(offset, end)..end]);
+        }
+
+        let uncompressed = lzxpress::lznt1::decompress(TEST_LZNT1_COMPRESSED_DATA).unwrap();
+        let range = lzxpress::lznt1::decompress_range(TEST_LZNT1_COMPRESSED_DATA, 0x1234, 0x3000).unwrap();
+        assert_eq!(range, uncompressed[0x1234..0x4234]);
+
+        let range = lzxpress::lznt1::decompress_range(&compressed, 0x1000, usize::MAX).unwrap();
+        assert_eq!(range, in_buf[0x1000..]);
+
+        // A copy token as the first token of a chunk
+        assert_eq!(lzxpress::lznt1::decompress_range(b"\x02\xb0\x01\x00\x00", 0, 0x10), Err(lzxpress::error::Error::CorruptedData));
+
+        // Short chunks in the middle of the stream are not padded
+        let mut compressed = lzxpress::lznt1::compress(b"hello world hello world").unwrap();
+        compressed.extend_from_slice(&lzxpress::lznt1::compress(b"world hello world hello").unwrap());
+        let uncompressed = lzxpress::lznt1::decompress(&compressed).unwrap();
+        assert_eq!(uncompressed.len(), 46);
+        for (offset, len) in [(0, 100), (20, 10), (23, 10), (40, 10), (46, 10)] {
+            let range = lzxpress::lznt1::decompress_range(&compressed, offset, len).unwrap();
+            let end = std::cmp::min(uncompressed.len(), offset + len);
+            assert_eq!(range, uncompressed[std::cmp::min(offset, end)..end]);
+        }
+    }
+
+    #[test]
+    fn test_lznt1_chunks() {
+        use std::borrow::Cow;
+
+        let mut in_buf = repeated(TEST_STRING.as_bytes(), 0x1000);
+        in_buf.extend_from_slice(TEST_STRING2.as_bytes());
+        let compressed = lzxpress::lznt1::compress(&in_buf).unwrap();
+
+        let chunks: Vec<_> = lzxpress::lznt1::chunks(&compressed).collect::<Result<_, _>>().unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        assert_eq!(chunks[0].offset, 0);
+        assert!(chunks[0].compressed);
+        assert_eq!(chunks[0].signature, 0x3000);
+        assert_eq!(chunks[0].decompress().unwrap(), &in_buf[..0x1000]);
+
+        // Too short to compress, stored and borrowed from the input
+        assert_eq!(chunks[1].offset, 2 + chunks[0].size);
+        assert!(!chunks[1].compressed);
+        assert_eq!(chunks[1].size, TEST_STRING2.len());
+        assert!(matches!(chunks[1].decompress().unwrap(), Cow::Borrowed(b) if b == TEST_STRING2.as_bytes()));
+
+        // A zero header ends the stream, a truncated chunk is an error.
+        let mut terminated = compressed.clone();
+        terminated.extend_from_slice(&[0, 0, 0xff]);
+        assert_eq!(lzxpress::lznt1::chunks(&terminated).count(), 2);
+
+        let errors: Vec<_> = lzxpress::lznt1::chunks(&compressed[..compressed.len() - 1]).collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1], Err(lzxpress::error::Error::MemLimit));
+    }
+
+    #[test]
+    fn test_data_tokens() {
+        use lzxpress::data::{Token, TokenKind};
+
+        let tokens: Vec<Token> = lzxpress::data::tokens(TEST_DATA3).collect::<Result<_, _>>().unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[0], Token { kind: TokenKind::Literal(b'a'), in_idx: 4, out_idx: 0, flag_idx: 0, flag_bit: 31, nibble_idx: None });
+        assert_eq!(tokens[2].kind, TokenKind::Literal(b'c'));
+        assert_eq!(tokens[3], Token {
+            kind: TokenKind::Match { offset: 3, length: TEST_STRING3.len() - 3 },
+            in_idx: 7,
+            out_idx: 3,
+            flag_idx: 0,
+            flag_bit: 28,
+            nibble_idx: Some(9),
+        });
+
+        // Replaying the tokens gives back what decompress() produces.
+        for in_buf in [TEST_DATA, TEST_DATA2, TEST_DATA3] {
+            let mut out_buf: Vec<u8> = Vec::new();
+            for token in lzxpress::data::tokens(in_buf) {
+                let token = token.unwrap();
+                assert_eq!(token.out_idx, out_buf.len());
+                match token.kind {
+                    TokenKind::Literal(b) => out_buf.push(b),
+                    TokenKind::Match { offset, length } => {
+                        for _i in 0..length {
+                            out_buf.push(out_buf[out_buf.len() - offset]);
+                        }
+                    },
+                }
+            }
+            assert_eq!(out_buf, lzxpress::data::decompress(in_buf).unwrap());
+        }
+
+        let errors: Vec<_> = lzxpress::data::tokens(&TEST_DATA3[..9]).collect();
+        assert_eq!(errors.last(), Some(&Err(lzxpress::error::Error::MemLimit)));
+    }
+
+    #[test]
+    fn test_encode_tokens() {
+        use lzxpress::data::TokenKind;
+        use lzxpress::error::Error;
+
+        // Same stream as compress()
+        let tokens: Vec<TokenKind> = lzxpress::data::tokens(TEST_DATA).map(|t| t.unwrap().kind).collect();
+        assert_eq!(lzxpress::data::encode_tokens(&tokens).unwrap(), lzxpress::data::compress(TEST_STRING.as_bytes()).unwrap());
+        assert_eq!(lzxpress::data::encode_tokens(&[]).unwrap(), lzxpress::data::compress(b"").unwrap());
+
+        // Offset of exactly 8192 and the 16 and 32-bit length forms
+        let mut tokens: Vec<TokenKind> = (0..8192).map(|i| TokenKind::Literal(i as u8)).collect();
+        tokens.push(TokenKind::Match { offset: 8192, length: 300 });
+        tokens.push(TokenKind::Match { offset: 1, length: 0x10000 + 3 });
+        tokens.push(TokenKind::Match { offset: 2, length: 10 });
+
+        let uncompressed = lzxpress::data::decompress(&lzxpress::data::encode_tokens(&tokens).unwrap()).unwrap();
+        assert_eq!(uncompressed.len(), 8192 + 300 + 0x10003 + 10);
+        assert_eq!(uncompressed[8192..8492], uncompressed[..300]);
+
+        assert_eq!(lzxpress::data::encode_tokens(&[TokenKind::Match { offset: 8193, length: 3 }]), Err(Error::CorruptedData));
+
+        // LZNT1: a copy token at position 16 still has 12 bits of length,
+        // the next token starts a new chunk.
+        let mut tokens: Vec<TokenKind> = (0..16).map(|i| TokenKind::Literal(b'a' + i)).collect();
+        tokens.push(TokenKind::Match { offset: 16, length: 0x1000 - 16 });
+        tokens.push(TokenKind::Literal(b'z'));
+        let compressed = lzxpress::lznt1::encode_tokens(&tokens).unwrap();
+        assert_eq!(&compressed[..2], &[0x14, 0xb0]);
+        assert_eq!(&compressed[23..], &[0x01, 0xb0, 0x00, b'z']);
+        let uncompressed = lzxpress::lznt1::decompress_range(&compressed, 0, 0x2000).unwrap();
+        assert_eq!(uncompressed.len(), 0x1001);
+        assert_eq!(uncompressed[0xff0..0x1000], uncompressed[..16]);
+
+        // A match crossing the chunk boundary
+        let mut tokens: Vec<TokenKind> = vec![TokenKind::Literal(b'a'); 4090];
+        tokens.push(TokenKind::Match { offset: 1, length: 10 });
+        assert_eq!(lzxpress::lznt1::encode_tokens(&tokens), Err(Error::CorruptedData));
+        assert_eq!(lzxpress::lznt1::encode_tokens(&[TokenKind::Match { offset: 1, length: 3 }]), Err(Error::CorruptedData));
+
+        // 4096 literals and their flag bytes do not fit in a compressed chunk,
+        // 3640 do.
+        let literals = noise(0x4141_4141, 0x1000);
+        let tokens: Vec<TokenKind> = literals.iter().map(|b| TokenKind::Literal(*b)).collect();
+        assert_eq!(lzxpress::lznt1::encode_tokens(&tokens), Err(Error::CorruptedData));
+        assert_eq!(lzxpress::lznt1::encode_tokens(&tokens[..3640]).unwrap().len(), 2 + 3640 + 455);
+    }
+
+    #[test]
+    fn test_validate() {
+        use lzxpress::data::StreamInfo;
+        use lzxpress::error::Error;
+
+        for (in_buf, out_str) in [(TEST_DATA, TEST_STRING), (TEST_DATA2, TEST_STRING2), (TEST_DATA3, TEST_STRING3)] {
+            assert_eq!(lzxpress::data::validate(in_buf), Ok(StreamInfo { compressed_size: in_buf.len(), uncompressed_size: out_str.len() }));
+        }
+
+        // Back-reference before the start of the output
+        let bad = lzxpress::data::encode_tokens(&[lzxpress::data::TokenKind::Match { offset: 1, length: 3 }]).unwrap();
+        assert_eq!(lzxpress::data::validate(&bad), Err(Error::CorruptedData));
+        assert_eq!(lzxpress::data::validate(&TEST_DATA3[..9]), Err(Error::MemLimit));
+
+        let uncompressed = lzxpress::lznt1::decompress(TEST_LZNT1_COMPRESSED_DATA).unwrap();
+        let info = lzxpress::lznt1::validate(TEST_LZNT1_COMPRESSED_DATA).unwrap();
+        assert_eq!(info, StreamInfo { compressed_size: TEST_LZNT1_COMPRESSED_DATA.len(), uncompressed_size: uncompressed.len() });
+
+        // The zero header ends the stream, whatever follows is not looked at.
+        let mut terminated = lzxpress::lznt1::compress(TEST_STRING3.as_bytes()).unwrap();
+        let compressed_size = terminated.len() + 2;
+        terminated.extend_from_slice(&[0, 0, 0xff, 0xff, 0xff]);
+        assert_eq!(lzxpress::lznt1::validate(&terminated), Ok(StreamInfo { compressed_size, uncompressed_size: TEST_STRING3.len() }));
+
+        assert_eq!(lzxpress::lznt1::validate(b"\x02\xb0\x01\x00\x00"), Err(Error::CorruptedData));
+    }
+
+    #[test]
+    fn test_decompress_consumed() {
+        // Plain LZ77 stops at the given output length.
+        let mut in_buf = TEST_DATA.to_vec();
+        in_buf.extend_from_slice(b"trailer");
+        let (uncompressed, consumed) = lzxpress::data::decompress_consumed(&in_buf, TEST_STRING.len()).unwrap();
+        assert_eq!(uncompressed, TEST_STRING.as_bytes());
+        assert_eq!(consumed, TEST_DATA.len());
+        assert_eq!(&in_buf[consumed..], b"trailer");
+
+        // A match running past the output length
+        assert_eq!(lzxpress::data::decompress_consumed(TEST_DATA3, 10), Err(lzxpress::error::Error::MemLimit));
+
+        // LZNT1 stops at the zero header.
+        let mut in_buf = lzxpress::lznt1::compress(TEST_STRING3.as_bytes()).unwrap();
+        let compressed_size = in_buf.len() + 2;
+        in_buf.extend_from_slice(b"\x00\x00trailer");
+        let (uncompressed, consumed) = lzxpress::lznt1::decompress_consumed(&in_buf).unwrap();
+        assert_eq!(uncompressed, TEST_STRING3.as_bytes());
+        assert_eq!(consumed, compressed_size);
+
+        let (uncompressed, consumed) = lzxpress::lznt1::decompress_consumed(TEST_LZNT1_COMPRESSED_DATA).unwrap();
+        assert_eq!(uncompressed, lzxpress::lznt1::decompress(TEST_LZNT1_COMPRESSED_DATA).unwrap());
+        assert_eq!(consumed, TEST_LZNT1_COMPRESSED_DATA.len());
+    }
+
+    #[test]
+    fn test_decompress_lenient() {
+        use lzxpress::data::{DecodeFailure, TokenKind};
+        use lzxpress::error::Error;
+
+        let partial = lzxpress::data::decompress_lenient(TEST_DATA);
+        assert!(partial.is_complete());
+        assert_eq!(partial.out_buf, TEST_STRING.as_bytes());
+
+        let tokens = [TokenKind::Literal(b'a'), TokenKind::Literal(b'b'), TokenKind::Match { offset: 10, length: 3 }, TokenKind::Literal(b'c')];
+        let partial = lzxpress::data::decompress_lenient(&lzxpress::data::encode_tokens(&tokens).unwrap());
+        assert!(!partial.is_complete());
+        assert_eq!(partial.out_buf, b"ab");
+        assert_eq!(partial.failure, Some(DecodeFailure { error: Error::CorruptedData, in_idx: 6, out_idx: 2 }));
+
+        let partial = lzxpress::data::decompress_lenient(&TEST_DATA3[..9]);
+        assert_eq!(partial.out_buf, b"abc");
+        assert_eq!(partial.failure, Some(DecodeFailure { error: Error::MemLimit, in_idx: 7, out_idx: 3 }));
+
+        // LZNT1: the first chunk and what the second one decoded are kept.
+        let mut tokens = vec![TokenKind::Literal(b'a'), TokenKind::Match { offset: 1, length: 0xfff }];
+        tokens.push(TokenKind::Literal(b'x'));
+        tokens.push(TokenKind::Match { offset: 1, length: 3 });
+        let mut compressed = lzxpress::lznt1::encode_tokens(&tokens).unwrap();
+        let second = compressed.len() - 6;
+        compressed[second + 4] = 0x00;
+        compressed[second + 5] = 0x10;
+
+        let partial = lzxpress::lznt1::decompress_lenient(&compressed);
+        assert_eq!(partial.out_buf.len(), 0x1001);
+        assert_eq!(partial.out_buf[0x1000], b'x');
+        assert_eq!(partial.failure, Some(DecodeFailure { error: Error::CorruptedData, in_idx: second, out_idx: 0x1001 }));
+
+        let partial = lzxpress::lznt1::decompress_lenient(&compressed[..second + 3]);
+        assert_eq!(partial.out_buf.len(), 0x1000);
+        assert_eq!(partial.failure.unwrap().error, Error::MemLimit);
+    }
+
+    #[test]
+    fn test_lznt1_decompress_recover() {
+        use lzxpress::data::TokenKind;
+        use lzxpress::error::Error;
+        use lzxpress::lznt1::Damage;
+
+        let in_buf = repeated(TEST_STRING.as_bytes(), 0x3000);
+        let mut compressed = lzxpress::lznt1::compress(&in_buf).unwrap();
+        let chunks: Vec<_> = lzxpress::lznt1::chunks(&compressed).map(|c| c.unwrap().offset).collect();
+        assert_eq!(chunks.len(), 3);
+
+        let recovered = lzxpress::lznt1::decompress_recover(&compressed, 0);
+        assert_eq!(recovered.out_buf, in_buf);
+        assert!(recovered.damage.is_empty());
+
+        // Break a copy token of the second chunk: its first token is a literal,
+        // turn the whole flag byte into copy tokens.
+        compressed[chunks[1] + 2] = 0xff;
+        let recovered = lzxpress::lznt1::decompress_recover(&compressed, 0xcc);
+        assert_eq!(recovered.damage, vec![Damage { offset: chunks[1], out_range: 0x1000..0x2000, error: Error::CorruptedData }]);
+        assert_eq!(recovered.out_buf[..0x1000], in_buf[..0x1000]);
+        assert!(recovered.out_buf[0x1000..0x2000].iter().all(|b| *b == 0xcc));
+        assert_eq!(recovered.out_buf[0x2000..], in_buf[0x2000..]);
+
+        // A chunk running past the end of the input
+        let truncated = &compressed[..compressed.len() - 1];
+        let recovered = lzxpress::lznt1::decompress_recover(truncated, 0);
+        assert_eq!(recovered.out_buf.len(), 0x3000);
+        assert_eq!(recovered.damage.len(), 2);
+        assert_eq!(recovered.damage[1], Damage { offset: chunks[2], out_range: 0x2000..0x3000, error: Error::MemLimit });
+
+        let tokens = [TokenKind::Literal(b'a'), TokenKind::Match { offset: 1, length: 3 }];
+        assert_eq!(lzxpress::lznt1::decompress_recover(&lzxpress::lznt1::encode_tokens(&tokens).unwrap(), 0).out_buf, b"aaaa");
+    }
+
+    #[test]
+    fn test_carve_scan() {
+        use lzxpress::carve::{self, ScanOptions, StreamKind};
+
+        let text = words(0x2468_ace0, 0x2400, false);
+        let lznt1 = lzxpress::lznt1::compress(&text).unwrap();
+        let lz77 = lzxpress::data::compress(&text[..0x800]).unwrap();
+
+        let mut image = noise(0x1234_5678, 0x3001);
+        let lznt1_start = image.len();
+        image.extend_from_slice(&lznt1);
+        image.extend_from_slice(&[0, 0]);
+        image.extend_from_slice(&noise(0x8765_4321, 0x2000));
+        let lz77_start = image.len();
+        image.extend_from_slice(&lz77);
+        image.extend_from_slice(&noise(0x0bad_f00d, 0x2000));
+
+        let candidates = carve::scan(&mut Cursor::new(&image), &ScanOptions::default()).unwrap();
+
+        let found = candidates.iter().find(|c| c.kind == StreamKind::Lznt1).unwrap();
+        assert_eq!(found.start as usize, lznt1_start);
+        assert_eq!(found.end as usize, lznt1_start + lznt1.len() + 2);
+        assert_eq!(found.uncompressed_size, text.len());
+        assert!(found.confidence >= 0.75);
+
+        let found = candidates.iter().find(|c| c.kind == StreamKind::Lz77).unwrap();
+        assert_eq!(found.start as usize, lz77_start);
+        assert!(found.uncompressed_size >= 0x800);
+        assert!(found.confidence > 0.9);
+    }
+
+    #[test]
+    fn test_carve_scan_runs_and_noise() {
+        use lzxpress::carve::{self, ScanOptions};
+
+        for image in [vec![0u8; 0x20_0000], noise(0x1357_2468, 0x10_0000)] {
+            let candidates = carve::scan(&mut Cursor::new(&image), &ScanOptions::default()).unwrap();
+            assert!(candidates.is_empty(), "{:?}", candidates);
+        }
+
+        // The window is bounded
+        let options = ScanOptions { window: usize::MAX, ..ScanOptions::default() };
+        let error = carve::scan(&mut Cursor::new(&[0u8; 16]), &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_decompress_fragment() {
+        let text = words(0x1357_9bdf, 0x2800, true);
+        let compressed = lzxpress::data::compress(&text).unwrap();
+
+        // Cut the stream on a flag word past the second one, with no length
+        // nibble pending.
+        let tokens: Vec<lzxpress::data::Token> = lzxpress::data::tokens(&compressed).map(|t| t.unwrap()).collect();
+        let pending = |cut: &lzxpress::data::Token| {
+            let nibbles: Vec<usize> = tokens.iter().filter(|t| t.in_idx < cut.flag_idx).filter_map(|t| t.nibble_idx).collect();
+            nibbles.last().is_some_and(|last| nibbles.iter().filter(|n| *n == last).count() == 1)
+        };
+        let token = tokens.iter().filter(|t| t.flag_bit == 31).skip(2).find(|t| !pending(t)).unwrap();
+        assert_eq!(lzxpress::data::decompress(&compressed[token.flag_idx..]), Err(lzxpress::error::Error::CorruptedData));
+
+        // A match reaching to the far end of the 8 KiB window
+        let fragment = lzxpress::data::decompress_fragment(b"\x00\x00\x00\xc0\xf8\xff").unwrap();
+        assert_eq!(fragment, vec![None; 3]);
+
+        let fragment = lzxpress::data::decompress_fragment(&compressed[token.flag_idx..]).unwrap();
+        assert_eq!(fragment.len(), text.len() - token.out_idx);
+        assert!(fragment.iter().any(|b| b.is_none()));
+        assert!(fragment.iter().filter(|b| b.is_some()).count() > fragment.len() / 4);
+        for (i, b) in fragment.iter().enumerate() {
+            if let Some(b) = b {
+                assert_eq!(*b, text[token.out_idx + i]);
+            }
+        }
+
+        // From the start of the stream, nothing is missing.
+        let whole = lzxpress::data::decompress_fragment(TEST_DATA).unwrap();
+        assert_eq!(whole.iter().map(|b| b.unwrap()).collect::<Vec<u8>>(), TEST_STRING.as_bytes());
+    }
+
+    #[test]
+    fn test_decompress_fragment_candidates() {
+        let text = words(0x1357_9bdf, 0x2800, true);
+        let compressed = lzxpress::data::compress(&text).unwrap();
+
+        // Cut anywhere: inside a token, on a nibble byte, mid flag word
+        for cut in (37..compressed.len() / 2).step_by(997) {
+            let candidates = lzxpress::data::decompress_fragment_candidates(&compressed[cut..]);
+            assert!(candidates.windows(2).all(|pair| pair[0].in_idx < pair[1].in_idx));
+
+            let matching = candidates.iter().find(|candidate| {
+                let len = candidate.out_buf.len();
+                len > text.len() / 4 && len <= text.len() &&
+                    candidate.out_buf.iter().zip(&text[text.len() - len..]).all(|(b, t)| b.is_none() || *b == Some(*t))
+            });
+            assert!(matching.is_some(), "no candidate for cut {}", cut);
+        }
+
+        // From the start of the stream, the first candidate is the stream.
+        let candidates = lzxpress::data::decompress_fragment_candidates(TEST_DATA);
+        assert_eq!(candidates[0].in_idx, 0);
+        assert_eq!(candidates[0].out_buf.iter().map(|b| b.unwrap()).collect::<Vec<u8>>(), TEST_STRING.as_bytes());
+    }
+
+    #[test]
+    fn test_provenance() {
+        use lzxpress::data::{ProvenanceRun, Source, TokenKind};
+
+        let (uncompressed, provenance) = lzxpress::data::decompress_with_provenance(TEST_DATA).unwrap();
+        assert_eq!(uncompressed, TEST_STRING.as_bytes());
+        assert_eq!(provenance.runs(), &[
+            ProvenanceRun { out_idx: 0, len: 5, source: Source::Literal { in_idx: 4 } },
+            ProvenanceRun { out_idx: 5, len: 3, source: Source::Match { in_idx: 9, src_out_idx: 2 } },
+            ProvenanceRun { out_idx: 8, len: 12, source: Source::Literal { in_idx: 11 } },
+            ProvenanceRun { out_idx: 20, len: 14, source: Source::Match { in_idx: 23, src_out_idx: 0 } },
+            ProvenanceRun { out_idx: 34, len: 4, source: Source::Literal { in_idx: 26 } },
+        ]);
+        assert_eq!(provenance.lookup(10), Some(Source::Literal { in_idx: 13 }));
+        assert_eq!(provenance.lookup(21), Some(Source::Match { in_idx: 23, src_out_idx: 1 }));
+        assert_eq!(provenance.lookup(TEST_STRING.len()), None);
+
+        let tokens = [TokenKind::Literal(b'a'), TokenKind::Literal(b'b'), TokenKind::Match { offset: 2, length: 4 }, TokenKind::Literal(b'c')];
+        let mut compressed = lzxpress::lznt1::encode_tokens(&tokens).unwrap();
+        compressed.extend_from_slice(&lzxpress::lznt1::compress(TEST_STRING2.as_bytes()).unwrap());
+
+        let (uncompressed, provenance) = lzxpress::lznt1::decompress_with_provenance(&compressed).unwrap();
+        assert_eq!(&uncompressed[..7], b"abababc");
+        assert_eq!(provenance.runs(), &[
+            ProvenanceRun { out_idx: 0, len: 2, source: Source::Literal { in_idx: 3 } },
+            ProvenanceRun { out_idx: 2, len: 4, source: Source::Match { in_idx: 5, src_out_idx: 0 } },
+            ProvenanceRun { out_idx: 6, len: 1, source: Source::Literal { in_idx: 7 } },
+            ProvenanceRun { out_idx: 7, len: TEST_STRING2.len(), source: Source::Literal { in_idx: 10 } },
+        ]);
+        assert_eq!(provenance.lookup(9), Some(Source::Literal { in_idx: 12 }));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        use lzxpress::data::TokenKind;
+        use lzxpress::fingerprint::{self, Encoder, Padding};
+
+        // Samba pads the last flag word with zeros, compress() with ones.
+        let report = fingerprint::analyze_data(TEST_DATA).unwrap();
+        assert_eq!((report.literals, report.matches, report.longest_match, report.farthest_match), (21, 2, 14, 20));
+        assert_eq!(report.padding, Some(Padding::Zeros));
+        assert_eq!(report.best(), Some(Encoder::Samba));
+
+        let report = fingerprint::analyze_data(&lzxpress::data::compress(TEST_STRING.as_bytes()).unwrap()).unwrap();
+        assert_eq!(report.padding, Some(Padding::Ones));
+        assert_eq!(report.best(), Some(Encoder::Crate));
+        assert_eq!(report.scores[0].agreement, 1.0);
+
+        // Samba caps matches at 280 bytes
+        let report = fingerprint::analyze_data(TEST_DATA3).unwrap();
+        assert_eq!(report.longest_match, 297);
+        assert_eq!(report.best(), Some(Encoder::Crate));
+        assert!(report.scores[1].agreement < 0.75);
+
+        let in_buf = TEST_LZNT1_STRING1.repeat(20);
+        let report = fingerprint::analyze_lznt1(&lzxpress::lznt1::compress(in_buf.as_bytes()).unwrap()).unwrap();
+        assert_eq!(report.padding, None);
+        assert_eq!(report.best(), Some(Encoder::Crate));
+        assert_eq!(report.scores[0].agreement, 1.0);
+
+        // "abcdXabcYabcd": the closest "abc" at the end rather than the longest "abcd"
+        let mut tokens: Vec<TokenKind> = b"abcdX".iter().map(|&b| TokenKind::Literal(b)).collect();
+        tokens.extend_from_slice(&[
+            TokenKind::Match { offset: 5, length: 3 },
+            TokenKind::Literal(b'Y'),
+            TokenKind::Match { offset: 4, length: 3 },
+            TokenKind::Literal(b'd'),
+        ]);
+        let report = fingerprint::analyze_lznt1(&lzxpress::lznt1::encode_tokens(&tokens).unwrap()).unwrap();
+        let crate_score = report.scores.iter().find(|score| score.encoder == Encoder::Crate).unwrap();
+
+        // Only the last match tells the searches apart. The heuristic model
+        // explains it better but is never named.
+        assert_eq!(crate_score.agreement, 0.0);
+        assert!(Encoder::Ntdll.is_heuristic());
+        assert_eq!(report.best(), None);
+
+        // Stored chunks only: nothing to go on
+        let report = fingerprint::analyze_lznt1(&lzxpress::lznt1::compress(TEST_STRING2.as_bytes()).unwrap()).unwrap();
+        assert_eq!(report.best(), None);
+    }
+
+    #[test]
+    fn test_compress_samba() {
+        use lzxpress::data::CompressOptions;
+
+        // Matches stop at 280 bytes
+        let compressed = lzxpress::data::compress_with(TEST_STRING3.as_bytes(), &CompressOptions::samba()).unwrap();
+        assert_eq!(compressed, &[
+            0x00, 0x00, 0x00, 0x18, 0x61, 0x62, 0x63, 0x17,
+            0x00, 0x7f, 0xff, 0x15, 0x01, 0x17, 0x00 ][..]);
+        assert_eq!(lzxpress::data::decompress(&compressed).unwrap(), TEST_STRING3.as_bytes());
+
+        // A full last flag word is followed by an empty zeroed one
+        let literals: Vec<u8> = (0..32).collect();
+        let compressed = lzxpress::data::compress_with(&literals, &CompressOptions::samba()).unwrap();
+        assert_eq!(compressed.len(), 4 + 32 + 4);
+        assert_eq!(&compressed[36..], &[0, 0, 0, 0]);
+        assert_eq!(lzxpress::data::decompress(&compressed).unwrap(), literals);
+
+        let report = lzxpress::fingerprint::analyze_data(&compressed).unwrap();
+        assert_eq!(report.padding, Some(lzxpress::fingerprint::Padding::Zeros));
+
+        let options = CompressOptions { max_offset: 0, ..CompressOptions::default() };
+        assert_eq!(lzxpress::data::compress_with(&literals, &options), Err(lzxpress::error::Error::InvalidParameter));
+    }
 }
\ No newline at end of file